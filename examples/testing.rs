@@ -1,8 +1,10 @@
+use futures::StreamExt;
 use jsonrpsee::http_client::HttpClientBuilder;
 use opensips_client::*;
-use tracing::info;
+use opensips_client::stream::OverflowPolicy;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
 use std::net::SocketAddr;
 
 
@@ -16,25 +18,19 @@ async fn main() -> anyhow::Result<()> {
 	let url = "http://127.0.0.1:28888/mi";
 
 	let socket = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 10000))).await?;
-	let notifier = UdpNotificationReceiver { socket };
-	let (tx, mut rx) = mpsc::channel(1);
-	tokio::spawn(notifier.run(move |n| {
-		let tx = tx.clone();
-		async move {
-			tx.send(n).await;
-			()
-		}
-	}));
+	let notifier = UdpNotificationReceiver::new(socket);
+	let mut notifications = notifier.into_stream(64, OverflowPolicy::Backpressure, CancellationToken::new());
 
 	let client = HttpClientBuilder::default()
 		.build(url)?;
 	client.event_subscribe("E_UA_SESSION".to_string(), "udp:127.0.0.1:10000".to_string()).await?;
 
-	loop {
-		if let Some(thing) = rx.recv().await {
-			info!("received: {:?}", thing);
+	while let Some(item) = notifications.next().await {
+		match item {
+			Ok(received) => info!("received from {}: {:?}", received.source, received.notification),
+			Err(e) => warn!("{}", e),
 		}
 	}
 
-	// Ok(())
+	Ok(())
 }
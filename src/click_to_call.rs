@@ -0,0 +1,454 @@
+//! Click-to-call: ring one party, and once they answer connect them to a second one.
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use super::*;
+use super::sip::{NameAddr, SipHeaders};
+use super::ua_session::{Outbound, UaSessionError, UaSessionHandle, UaSessionManager};
+
+fn random_token() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    format!("{:016x}", hasher.finish())
+}
+
+/// The host of a SIP URI, without user, port or parameters.
+fn uri_host(uri: &str) -> &str {
+    let rest = uri.split_once(':').map_or(uri, |(_, rest)| rest);
+    let rest = rest.rsplit_once('@').map_or(rest, |(_, host)| host);
+    let host = rest.split([';', '?', '>']).next().unwrap_or_default();
+    match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+}
+
+// a status that doesn't parse counts as a failure, reported with code 0 and the raw text
+fn status_of(response: &TUacDlgResponse) -> tm::Status {
+    response.status().unwrap_or_else(|_| tm::Status { code: 0, reason: response.status.clone() })
+}
+
+/// A UAC dialog's identifiers, for building the headers `t_uac_dlg` needs.
+#[derive(Clone, Debug)]
+pub struct UacDialog {
+    pub call_id: String,
+    pub from: NameAddr,
+    pub to: NameAddr,
+    pub contact: String,
+    /// where in-dialog requests go, the remote Contact once known
+    pub remote_target: String,
+    /// Route headers for in-dialog requests, from the reply's Record-Route
+    pub route: Vec<String>,
+}
+
+impl UacDialog {
+    /// A new dialog with a random Call-ID and From tag.
+    pub fn new(from: &str, to: &str, contact: &str) -> Self {
+        let mut from = NameAddr::parse(from).unwrap_or_else(|| NameAddr { uri: from.to_string(), ..Default::default() });
+        from.params.retain(|(k, _)| !k.eq_ignore_ascii_case("tag"));
+        from.params.push(("tag".to_string(), Some(random_token())));
+        let to = NameAddr::parse(to).unwrap_or_else(|| NameAddr { uri: to.to_string(), ..Default::default() });
+        let host = match uri_host(&from.uri) {
+            "" => "localhost",
+            host => host,
+        };
+        UacDialog {
+            call_id: format!("{}@{}", random_token(), host),
+            remote_target: to.uri.clone(),
+            from,
+            to,
+            contact: contact.to_string(),
+            route: Vec::new(),
+        }
+    }
+
+    /// Headers for a request; `cseq` is the sequence number to use.
+    pub fn headers(&self, method: &str, cseq: u32) -> SipHeaders {
+        let mut headers = SipHeaders::new();
+        for route in &self.route {
+            headers.add("Route", route.as_str());
+        }
+        headers
+            .add("From", self.from.to_string())
+            .add("To", self.to.to_string())
+            .add("Call-ID", self.call_id.as_str())
+            .add("CSeq", format!("{} {}", cseq, method))
+            .add("Contact", format!("<{}>", self.contact));
+        headers
+    }
+
+    /// Takes the To tag, remote target and route set from a 2xx to the INVITE.
+    pub fn confirm(&mut self, reply: &SipHeaders) {
+        if let Some(to) = reply.get("To").and_then(NameAddr::parse) {
+            self.to = to;
+        }
+        if let Some(contact) = reply.contact().first() {
+            self.remote_target = contact.uri.clone();
+        }
+        let mut route: Vec<String> = reply.get_all("Record-Route").into_iter().map(str::to_string).collect();
+        route.reverse();
+        self.route = route;
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallLeg {
+    First,
+    Second,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClickToCallProgress {
+    Calling { leg: CallLeg, uri: String },
+    Provisional { leg: CallLeg, code: usize, reason: String },
+    Answered { leg: CallLeg },
+    /// The REFER was accepted; `code` is 0 when sent through ua_session, which doesn't report it.
+    Referred { code: usize },
+    Bridged,
+    HungUp { leg: CallLeg },
+}
+
+#[derive(Debug)]
+pub enum ClickToCallError {
+    Rpc(jsonrpsee::core::Error),
+    UaSession(UaSessionError),
+    /// A leg, or the REFER, got a failure reply.
+    Failed { leg: CallLeg, code: usize, reason: String },
+    /// The session ended before it was answered, with no reply seen.
+    Ended { leg: CallLeg },
+}
+
+impl fmt::Display for ClickToCallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClickToCallError::Rpc(e) => write!(f, "MI error: {}", e),
+            ClickToCallError::UaSession(e) => write!(f, "{}", e),
+            ClickToCallError::Failed { leg, code, reason } => write!(f, "{:?} leg failed: {} {}", leg, code, reason),
+            ClickToCallError::Ended { leg } => write!(f, "{:?} leg ended before answering", leg),
+        }
+    }
+}
+
+impl std::error::Error for ClickToCallError {}
+
+impl From<jsonrpsee::core::Error> for ClickToCallError {
+    fn from(e: jsonrpsee::core::Error) -> Self {
+        ClickToCallError::Rpc(e)
+    }
+}
+
+impl From<UaSessionError> for ClickToCallError {
+    fn from(e: UaSessionError) -> Self {
+        ClickToCallError::UaSession(e)
+    }
+}
+
+pub enum ClickToCallMode<C: OpenSIPSClient + Send + Sync + 'static> {
+    /// INVITE the first party with `t_uac_dlg`, ACK, REFER them to the second and BYE.
+    TUacDlgRefer,
+    /// Call the first party through ua_session and REFER them to the second.
+    UaSessionRefer(UaSessionManager<C>),
+    /// Call both parties through ua_session and re-INVITE the first with the second's SDP
+    /// (third party call control, RFC 3725).
+    UaSessionBridge(UaSessionManager<C>),
+}
+
+/// Sessions still up after a ua_session click-to-call.
+pub struct ClickToCallLegs<C: OpenSIPSClient + Send + Sync + 'static> {
+    pub first: Option<UaSessionHandle<C>>,
+    pub second: Option<UaSessionHandle<C>>,
+}
+
+pub struct ClickToCall<C: OpenSIPSClient + Send + Sync + 'static> {
+    client: Arc<C>,
+    mode: ClickToCallMode<C>,
+    from: String,
+    first: String,
+    second: String,
+    contact: String,
+    next_hop: String,
+    socket: String,
+    body: String,
+    content_type: String,
+    progress: Option<mpsc::Sender<ClickToCallProgress>>,
+}
+
+impl<C: OpenSIPSClient + Send + Sync + 'static> ClickToCall<C> {
+    /// Calls `first`, then connects them to `second`; both are SIP URIs.
+    pub fn new(client: Arc<C>, mode: ClickToCallMode<C>, first: &str, second: &str) -> Self {
+        ClickToCall {
+            client,
+            mode,
+            from: "sip:click-to-call@localhost".to_string(),
+            first: first.to_string(),
+            second: second.to_string(),
+            contact: "sip:click-to-call@localhost".to_string(),
+            next_hop: String::new(),
+            socket: String::new(),
+            body: String::new(),
+            content_type: String::new(),
+            progress: None,
+        }
+    }
+
+    /// Who the calls appear to come from.
+    pub fn from(mut self, from: &str) -> Self {
+        self.from = from.to_string();
+        self
+    }
+
+    pub fn contact(mut self, contact: &str) -> Self {
+        self.contact = contact.to_string();
+        self
+    }
+
+    pub fn next_hop(mut self, next_hop: &str) -> Self {
+        self.next_hop = next_hop.to_string();
+        self
+    }
+
+    pub fn socket(mut self, socket: &str) -> Self {
+        self.socket = socket.to_string();
+        self
+    }
+
+    /// Offer for the first leg, e.g. a hold or media server SDP.
+    pub fn body(mut self, body: &str, content_type: &str) -> Self {
+        self.body = body.to_string();
+        self.content_type = content_type.to_string();
+        self
+    }
+
+    pub fn progress(mut self, progress: mpsc::Sender<ClickToCallProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    async fn report(&self, progress: ClickToCallProgress) {
+        debug!("click-to-call {} -> {}: {:?}", self.first, self.second, progress);
+        if let Some(tx) = &self.progress {
+            let _ = tx.send(progress).await;
+        }
+    }
+
+    pub async fn run(self) -> Result<ClickToCallLegs<C>, ClickToCallError> {
+        match &self.mode {
+            ClickToCallMode::TUacDlgRefer => {
+                self.run_t_uac_dlg().await?;
+                Ok(ClickToCallLegs { first: None, second: None })
+            }
+            ClickToCallMode::UaSessionRefer(manager) => {
+                let mut first = self.call(manager, CallLeg::First, &self.first, &self.body, &self.content_type).await?.0;
+                let refer_to = vec![
+                    format!("Refer-To: <{}>", self.second),
+                    format!("Referred-By: <{}>", self.from),
+                ];
+                if let Err(e) = first.update("REFER", "", "", refer_to).await {
+                    self.hang_up(CallLeg::First, &mut first).await;
+                    return Err(e.into());
+                }
+                self.report(ClickToCallProgress::Referred { code: 0 }).await;
+                Ok(ClickToCallLegs { first: Some(first), second: None })
+            }
+            ClickToCallMode::UaSessionBridge(manager) => {
+                let (mut first, first_sdp) = self.call(manager, CallLeg::First, &self.first, &self.body, &self.content_type).await?;
+                let second = self.call(manager, CallLeg::Second, &self.second, &first_sdp, "application/sdp").await;
+                let (mut second, second_sdp) = match second {
+                    Ok(second) => second,
+                    Err(e) => {
+                        self.hang_up(CallLeg::First, &mut first).await;
+                        return Err(e);
+                    }
+                };
+                if let Err(e) = first.update("INVITE", &second_sdp, "application/sdp", Vec::new()).await {
+                    self.hang_up(CallLeg::First, &mut first).await;
+                    self.hang_up(CallLeg::Second, &mut second).await;
+                    return Err(e.into());
+                }
+                self.report(ClickToCallProgress::Bridged).await;
+                Ok(ClickToCallLegs { first: Some(first), second: Some(second) })
+            }
+        }
+    }
+
+    // for legs that are up when the call can't go on
+    async fn hang_up(&self, leg: CallLeg, session: &mut UaSessionHandle<C>) {
+        match session.hangup().await {
+            Ok(()) => self.report(ClickToCallProgress::HungUp { leg }).await,
+            Err(e) => warn!("failed to hang up {:?} leg {}: {}", leg, session.key(), e),
+        }
+    }
+
+    /// Places one leg through ua_session and waits for the answer and its body.
+    async fn call(&self, manager: &UaSessionManager<C>, leg: CallLeg, uri: &str, body: &str, content_type: &str) -> Result<(UaSessionHandle<C>, String), ClickToCallError> {
+        self.report(ClickToCallProgress::Calling { leg, uri: uri.to_string() }).await;
+        let mut session = manager.start(Outbound {
+            ruri: uri.to_string(),
+            to: uri.to_string(),
+            from: self.from.clone(),
+            proxy: self.next_hop.clone(),
+            body: body.to_string(),
+            content_type: content_type.to_string(),
+            ..Default::default()
+        }).await?;
+        while let Some(event) = session.next().await {
+            match event.event_type {
+                UAEventType::Early => {
+                    self.report(ClickToCallProgress::Provisional { leg, code: event.status, reason: event.reason }).await;
+                }
+                UAEventType::Answered => {
+                    self.report(ClickToCallProgress::Answered { leg }).await;
+                    return Ok((session, event.body));
+                }
+                UAEventType::Rejected => {
+                    return Err(ClickToCallError::Failed { leg, code: event.status, reason: event.reason });
+                }
+                _ => {}
+            }
+        }
+        Err(ClickToCallError::Ended { leg })
+    }
+
+    async fn request(&self, dialog: &UacDialog, method: &str, cseq: u32, body: &str, content_type: &str) -> Result<TUacDlgResponse, ClickToCallError> {
+        let mut headers = dialog.headers(method, cseq);
+        let ruri = if method == "INVITE" && cseq == 1 { self.first.clone() } else { dialog.remote_target.clone() };
+        let response = if body.is_empty() {
+            self.client.t_uac_dlg(method.to_string(), ruri, headers.to_string(), self.next_hop.clone(), self.socket.clone()).await?
+        } else {
+            headers.add("Content-Type", content_type);
+            self.client.t_uac_dlg_with_body(method.to_string(), ruri, headers.to_string(), self.next_hop.clone(), self.socket.clone(), body.to_string()).await?
+        };
+        Ok(response)
+    }
+
+    async fn run_t_uac_dlg(&self) -> Result<(), ClickToCallError> {
+        let mut dialog = UacDialog::new(&self.from, &self.first, &self.contact);
+        self.report(ClickToCallProgress::Calling { leg: CallLeg::First, uri: self.first.clone() }).await;
+        // t_uac_dlg only returns once the INVITE has a final reply
+        let invite = self.request(&dialog, "INVITE", 1, &self.body, &self.content_type).await?;
+        let status = status_of(&invite);
+        if !status.is_success() {
+            return Err(ClickToCallError::Failed { leg: CallLeg::First, code: status.code as usize, reason: status.reason });
+        }
+        self.report(ClickToCallProgress::Answered { leg: CallLeg::First }).await;
+        if let Some(reply) = invite.reply() {
+            dialog.confirm(&reply.headers);
+        }
+        let transfer = self.transfer(&dialog).await;
+
+        // the transferee has its own call to the second party now, or failed to; either way the
+        // first leg is done with, including when the ACK or REFER couldn't be sent
+        let bye = self.request(&dialog, "BYE", 3, "", "").await;
+        if bye.is_ok() {
+            self.report(ClickToCallProgress::HungUp { leg: CallLeg::First }).await;
+        }
+        let response = transfer?;
+        bye?;
+        let status = status_of(&response);
+        if !status.is_success() {
+            return Err(ClickToCallError::Failed { leg: CallLeg::Second, code: status.code as usize, reason: status.reason });
+        }
+        Ok(())
+    }
+
+    // ACKs the answered INVITE and REFERs the first party to the second
+    async fn transfer(&self, dialog: &UacDialog) -> Result<TUacDlgResponse, ClickToCallError> {
+        self.request(dialog, "ACK", 1, "", "").await?;
+        let mut headers = dialog.headers("REFER", 2);
+        headers.add("Refer-To", format!("<{}>", self.second)).add("Referred-By", format!("<{}>", self.from));
+        let response = self.client.t_uac_dlg("REFER".to_string(), dialog.remote_target.clone(), headers.to_string(), self.next_hop.clone(), self.socket.clone()).await?;
+        let status = status_of(&response);
+        if status.is_success() {
+            self.report(ClickToCallProgress::Referred { code: status.code as usize }).await;
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::mock::{MockError, MockOpenSIPS};
+
+    #[tokio::test]
+    async fn test_t_uac_dlg_refer() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        mock.respond_with("t_uac_dlg", |params| Ok(match params["method"].as_str() {
+            Some("INVITE") => json!({
+                "Status": "200 OK",
+                "Message": "SIP/2.0 200 OK\r\nRecord-Route: <sip:10.0.0.9;lr>\r\nTo: <sip:alice@example.com>;tag=a1\r\nContact: <sip:alice@10.0.0.1:5060>\r\n\r\nv=0\r\n",
+            }),
+            Some("REFER") => json!({"Status": "202 Accepted", "Message": ""}),
+            _ => json!({"Status": "200 OK", "Message": ""}),
+        }));
+        let (tx, mut rx) = mpsc::channel(16);
+        ClickToCall::new(Arc::new(mock.client()), ClickToCallMode::TUacDlgRefer, "sip:alice@example.com", "sip:bob@example.com")
+            .from("\"Sales\" <sip:sales@example.com>")
+            .contact("sip:sales@10.0.0.5")
+            .progress(tx)
+            .run()
+            .await
+            .unwrap();
+
+        let calls = mock.calls("t_uac_dlg");
+        let methods: Vec<_> = calls.iter().map(|c| c["method"].as_str().unwrap()).collect();
+        assert_eq!(methods, vec!["INVITE", "ACK", "REFER", "BYE"]);
+        let invite = SipHeaders::parse(calls[0]["headers"].as_str().unwrap());
+        let call_id = invite.get("Call-ID").unwrap();
+        assert!(call_id.ends_with("@example.com"));
+        assert!(NameAddr::parse(invite.get("From").unwrap()).unwrap().tag().is_some());
+
+        let refer = SipHeaders::parse(calls[2]["headers"].as_str().unwrap());
+        assert_eq!(calls[2]["ruri"], "sip:alice@10.0.0.1:5060");
+        assert_eq!((refer.get("Call-ID"), refer.get("CSeq")), (Some(call_id), Some("2 REFER")));
+        assert_eq!(NameAddr::parse(refer.get("To").unwrap()).unwrap().tag(), Some("a1"));
+        assert_eq!(refer.get("Refer-To"), Some("<sip:bob@example.com>"));
+        let bye = SipHeaders::parse(calls[3]["headers"].as_str().unwrap());
+        assert_eq!(bye.get("Route"), Some("<sip:10.0.0.9;lr>"));
+
+        let mut progress = Vec::new();
+        while let Ok(p) = rx.try_recv() {
+            progress.push(p);
+        }
+        assert_eq!(progress[1..], [
+            ClickToCallProgress::Answered { leg: CallLeg::First },
+            ClickToCallProgress::Referred { code: 202 },
+            ClickToCallProgress::HungUp { leg: CallLeg::First },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_t_uac_dlg_refer_failure_hangs_up() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        mock.respond_with("t_uac_dlg", |params| match params["method"].as_str() {
+            Some("REFER") => Err(MockError::new(500, "Internal error")),
+            _ => Ok(json!({"Status": "200 OK", "Message": "To: <sip:alice@example.com>;tag=a1\r\n\r\n"})),
+        });
+        let result = ClickToCall::new(Arc::new(mock.client()), ClickToCallMode::TUacDlgRefer, "sip:alice@example.com", "sip:bob@example.com")
+            .from("<sip:sales@[2001:db8::1]:5061;transport=tls>")
+            .run()
+            .await;
+        assert!(matches!(result, Err(ClickToCallError::Rpc(_))));
+        let calls = mock.calls("t_uac_dlg");
+        let methods: Vec<_> = calls.iter().map(|c| c["method"].as_str().unwrap()).collect();
+        assert_eq!(methods, vec!["INVITE", "ACK", "REFER", "BYE"]);
+        let call_id = SipHeaders::parse(calls[0]["headers"].as_str().unwrap()).get("Call-ID").unwrap().to_string();
+        assert!(call_id.ends_with("@2001:db8::1"), "{}", call_id);
+    }
+
+    #[test]
+    fn test_uri_host() {
+        assert_eq!(uri_host("sip:alice@example.com:5060;transport=tcp"), "example.com");
+        assert_eq!(uri_host("sip:example.com;lr"), "example.com");
+        assert_eq!(uri_host("sips:[2001:db8::1]:5061"), "2001:db8::1");
+        assert_eq!(uri_host("sip:bob@10.0.0.1?subject=x"), "10.0.0.1");
+    }
+}
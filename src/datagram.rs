@@ -0,0 +1,167 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use jsonrpsee::core::{async_trait, Error};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+use tokio::sync::Mutex;
+use tracing::debug;
+use super::transport::{reply_matches, MiClient, MiTransport};
+
+// mi_datagram replies are limited to one datagram
+const MAX_DATAGRAM: usize = 65536;
+
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram, PathBuf),
+}
+
+/// Talks to OpenSIPS' `mi_datagram` module over UDP or a Unix datagram socket.
+///
+/// One request is in flight at a time; replies to earlier requests that timed out are discarded.
+pub struct DatagramTransport {
+    socket: Mutex<Socket>,
+}
+
+pub type DatagramMiClient = MiClient<DatagramTransport>;
+
+impl DatagramMiClient {
+    pub async fn udp(server: impl ToSocketAddrs) -> io::Result<Self> {
+        let server = lookup_host(server).await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for MI server"))?;
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(server).await?;
+        Ok(MiClient::new(DatagramTransport { socket: Mutex::new(Socket::Udp(socket)) }))
+    }
+
+    /// The client binds its own socket in the temp directory so OpenSIPS has somewhere to reply;
+    /// it is removed when the client is dropped.
+    #[cfg(unix)]
+    pub fn unix(server: impl AsRef<Path>) -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let local = std::env::temp_dir().join(format!(
+            "opensips-mi-{}-{}.sock", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_file(&local);
+        let socket = UnixDatagram::bind(&local)?;
+        // OpenSIPS usually runs as another user and needs write access to reply; bind is subject
+        // to the umask
+        std::fs::set_permissions(&local, std::os::unix::fs::PermissionsExt::from_mode(0o666))?;
+        socket.connect(server)?;
+        Ok(MiClient::new(DatagramTransport { socket: Mutex::new(Socket::Unix(socket, local)) }))
+    }
+}
+
+impl Drop for DatagramTransport {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Socket::Unix(_, path) = self.socket.get_mut() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl Socket {
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Udp(s) => s.send(buf).await,
+            #[cfg(unix)]
+            Socket::Unix(s, _) => s.send(buf).await,
+        }
+    }
+
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Udp(s) => s.recv(buf).await,
+            #[cfg(unix)]
+            Socket::Unix(s, _) => s.recv(buf).await,
+        }
+    }
+}
+
+#[async_trait]
+impl MiTransport for DatagramTransport {
+    async fn round_trip(&self, request: String, ids: &[u64]) -> Result<Vec<u8>, Error> {
+        let socket = self.socket.lock().await;
+        socket.send(request.as_bytes()).await
+            .map_err(|e| Error::Transport(e.into()))?;
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let size = socket.recv(&mut buf).await
+                .map_err(|e| Error::Transport(e.into()))?;
+            if reply_matches(&buf[..size], ids) {
+                buf.truncate(size);
+                return Ok(buf);
+            }
+            debug!("discarding stale MI reply of {} bytes", size);
+        }
+    }
+
+    async fn send(&self, notification: String) -> Result<(), Error> {
+        let socket = self.socket.lock().await;
+        socket.send(notification.as_bytes()).await
+            .map_err(|e| Error::Transport(e.into()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OpenSIPSClient;
+
+    // replies with a stale id first, then the real answer
+    fn answer(request: &[u8]) -> Vec<Vec<u8>> {
+        let request: serde_json::Value = serde_json::from_slice(request).unwrap();
+        assert_eq!(request["method"], "version");
+        let stale = serde_json::json!({"jsonrpc": "2.0", "id": 999, "result": {"Server": "old"}});
+        let reply = serde_json::json!({"jsonrpc": "2.0", "id": request["id"], "result": {"Server": "OpenSIPS (3.4.0 (x86_64/linux))"}});
+        vec![serde_json::to_vec(&stale).unwrap(), serde_json::to_vec(&reply).unwrap()]
+    }
+
+    #[tokio::test]
+    async fn test_udp_version() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            let (size, peer) = server.recv_from(&mut buf).await.unwrap();
+            for reply in answer(&buf[..size]) {
+                server.send_to(&reply, peer).await.unwrap();
+            }
+        });
+
+        let client = DatagramMiClient::udp(addr).await.unwrap();
+        assert_eq!(client.version().await.unwrap().server, "OpenSIPS (3.4.0 (x86_64/linux))");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_version() {
+        let path = std::env::temp_dir().join(format!("opensips-mi-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            let (size, peer) = server.recv_from(&mut buf).await.unwrap();
+            let peer = peer.as_pathname().unwrap().to_owned();
+            for reply in answer(&buf[..size]) {
+                server.send_to(&reply, &peer).await.unwrap();
+            }
+        });
+
+        let client = DatagramMiClient::unix(&path).unwrap();
+        let local = std::env::temp_dir().join(format!("opensips-mi-{}-0.sock", std::process::id()));
+        let mode = std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(local).unwrap().permissions());
+        assert_eq!(mode & 0o777, 0o666);
+        assert_eq!(client.version().await.unwrap().server, "OpenSIPS (3.4.0 (x86_64/linux))");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,118 @@
+//! Suppresses the same event reported by several nodes of a cluster.
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+use super::*;
+
+/// What identifies an event regardless of which node reported it, or `None` for events that
+/// are never treated as duplicates.
+pub fn identity_key(notification: &Notification) -> Option<String> {
+    let event = notification.event_name();
+    match notification {
+        Notification::EUlContactInsert(c)
+        | Notification::EUlContactDelete(c)
+        | Notification::EUlContactUpdate(c) => Some(format!("{}|{}|{}|{}", event, c.callid, c.cseq, c.aor)),
+        Notification::EDlgStateChanged(d) => Some(format!("{}|{}|{}", event, d.id, d.new_state as u8)),
+        Notification::EUaSession(s) => Some(format!("{}|{}|{:?}", event, s.key, s.event_type)),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Seen {
+    keys: HashMap<String, Instant>,
+    // insertion order, for expiring keys
+    order: VecDeque<(Instant, String)>,
+}
+
+/// Remembers identity keys for a window of time. Clones share state, so one `Deduplicator`
+/// can sit behind every receiver in a fleet.
+#[derive(Clone)]
+pub struct Deduplicator {
+    window: Duration,
+    seen: Arc<Mutex<Seen>>,
+    suppressed: Arc<AtomicU64>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Deduplicator { window, seen: Default::default(), suppressed: Default::default() }
+    }
+
+    /// True the first time an event is seen within the window.
+    pub fn is_new(&self, notification: &Notification) -> bool {
+        let Some(key) = identity_key(notification) else {
+            return true;
+        };
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        while let Some((at, _)) = seen.order.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            let (at, key) = seen.order.pop_front().expect("front exists");
+            // the key may have been refreshed since
+            if seen.keys.get(&key) == Some(&at) {
+                seen.keys.remove(&key);
+            }
+        }
+        if seen.keys.contains_key(&key) {
+            debug!("suppressing duplicate {}", key);
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        seen.keys.insert(key.clone(), now);
+        seen.order.push_back((now, key));
+        true
+    }
+
+    /// Duplicates suppressed so far.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Wraps a handler for `UdpNotificationReceiver::run` so it only sees new events.
+    pub fn filter<F, Fut>(&self, f: F) -> impl Fn(Notification) -> futures::future::OptionFuture<Fut>
+        where F: Fn(Notification) -> Fut,
+              Fut: Future<Output = ()>
+    {
+        let dedup = self.clone();
+        move |n| dedup.is_new(&n).then(|| f(n)).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dialog(id: &str, new_state: DialogState) -> Notification {
+        Notification::EDlgStateChanged(DialogChange {
+            id: id.into(),
+            call_id: "c1".into(),
+            from_tag: "f".into(),
+            to_tag: "t".into(),
+            old_state: DialogState::Early,
+            new_state,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_window_and_sharing() {
+        let dedup = Deduplicator::new(Duration::from_millis(50));
+        let other_receiver = dedup.clone();
+        assert!(dedup.is_new(&dialog("1", DialogState::Confirmed)));
+        assert!(!other_receiver.is_new(&dialog("1", DialogState::Confirmed)));
+        assert!(other_receiver.is_new(&dialog("1", DialogState::Deleted)));
+        assert_eq!(dedup.suppressed(), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(dedup.is_new(&dialog("1", DialogState::Confirmed)));
+
+        let change = ClustererNodeStateChange { cluster_id: 1, node_id: 2, new_state: ClusterNodeState::Up };
+        assert!(dedup.is_new(&Notification::EClustererNodeStateChange(change.clone())));
+        assert!(dedup.is_new(&Notification::EClustererNodeStateChange(change)));
+    }
+}
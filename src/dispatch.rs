@@ -0,0 +1,107 @@
+//! Background handling for consumers fed by a receiver, so that slow handlers don't hold up
+//! the socket.
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Semaphore;
+use tracing::{warn, Instrument};
+use super::*;
+
+/// How many notifications may wait for a handler, and how many may be handled at once.
+/// Notifications arriving while the queue is full are dropped and counted in the receiver's
+/// metrics.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DispatchQueue {
+    pub capacity: usize,
+    pub max_in_flight: usize,
+}
+
+impl Default for DispatchQueue {
+    fn default() -> Self {
+        DispatchQueue { capacity: 1024, max_in_flight: 64 }
+    }
+}
+
+impl DispatchQueue {
+    pub fn new(capacity: usize, max_in_flight: usize) -> Self {
+        DispatchQueue { capacity: capacity.max(1), max_in_flight: max_in_flight.max(1) }
+    }
+}
+
+impl UdpNotificationReceiver {
+    /// Like `run`, but each notification is handled in its own task. Handler latency is measured
+    /// in that task, so it covers the handler itself rather than the hand-off.
+    pub async fn run_queued<F, Fut>(self, queue: DispatchQueue, f: F) -> tokio::io::Result<()>
+        where F: Fn(Notification) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = ()> + Send + 'static
+    {
+        let metrics = self.metrics.clone();
+        let (queued_tx, mut queued) = mpsc::channel(queue.capacity);
+        let worker_metrics = metrics.clone();
+        tokio::spawn(async move {
+            let in_flight = Arc::new(Semaphore::new(queue.max_in_flight));
+            while let Some((notification, span)) = queued.recv().await {
+                let permit = in_flight.clone().acquire_owned().await.expect("semaphore is never closed");
+                let handled = f(notification).instrument(span);
+                let metrics = worker_metrics.clone();
+                tokio::spawn(async move {
+                    let started = Instant::now();
+                    handled.await;
+                    metrics.record_handler(started.elapsed());
+                    drop(permit);
+                });
+            }
+        });
+        self.receive(move |notification, span| {
+            if let Err(TrySendError::Full((notification, _))) = queued_tx.try_send((notification, span)) {
+                warn!("dispatch queue full, dropping {}", notification.event_name());
+                metrics.record_dropped();
+            }
+            async {}
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_run_queued_drops_and_times_handlers() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let receiver = UdpNotificationReceiver::new(socket);
+        let metrics = receiver.metrics.clone();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Notification>();
+        // one handler at a time, one waiting: the rest of the burst is dropped
+        tokio::spawn(receiver.run_queued(DispatchQueue::new(1, 1), move |n| {
+            let tx = tx.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = tx.send(n);
+            }
+        }));
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let batch: Vec<String> = (0..5)
+            .map(|node_id| format!(r#"{{"jsonrpc":"2.0","method":"E_CLUSTERER_NODE_STATE_CHANGE","params":{{"cluster_id":1,"node_id":{},"new_state":1}}}}"#, node_id))
+            .collect();
+        sender.send_to(format!("[{}]", batch.join(",")).as_bytes(), addr).await.unwrap();
+
+        let handled = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+        assert_eq!(handled.event_name(), "E_CLUSTERER_NODE_STATE_CHANGE");
+        let stats = loop {
+            let stats = metrics.snapshot();
+            if stats.handler_calls + stats.dropped == 5 {
+                break stats;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(stats.notifications["E_CLUSTERER_NODE_STATE_CHANGE"], 5);
+        assert!(stats.dropped >= 3);
+        assert!(stats.handler_latency_max >= Duration::from_millis(50));
+    }
+}
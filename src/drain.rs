@@ -0,0 +1,389 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{debug, warn};
+use super::*;
+use super::dispatcher::{DestinationState, SetState};
+
+/// How the drain decides how many calls are still using the destination.
+pub enum ActiveDialogs {
+    /// Never wait, go straight to maintenance once the destination is inactive.
+    Ignore,
+    /// Use the size of a dialog profile, usually one the script sets when routing to the destination.
+    Profile { profile: String, value: Option<String> },
+    /// Count the dialogs from `dlg_list` that match the filter.
+    Filter(Box<dyn Fn(&dialog::Dialog) -> bool + Send + Sync>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DrainProgress {
+    DryRun { state: DestinationState, active_dialogs: usize },
+    Disabled,
+    Waiting { active_dialogs: usize },
+    Drained,
+    TimedOut { active_dialogs: usize },
+    MaintenanceStarted,
+    MaintenanceFinished,
+    Enabled,
+    Confirmed,
+}
+
+#[derive(Debug)]
+pub enum DrainError {
+    Rpc(jsonrpsee::core::Error),
+    DestinationNotFound,
+    NotConfirmed(Option<DestinationState>),
+    /// Putting the destination back failed, so it is still inactive.
+    LeftInactive(Box<DrainError>),
+}
+
+impl fmt::Display for DrainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DrainError::Rpc(e) => write!(f, "MI error: {}", e),
+            DrainError::DestinationNotFound => write!(f, "destination not found in ds_list"),
+            DrainError::NotConfirmed(state) => write!(f, "destination not confirmed active (state {:?})", state),
+            DrainError::LeftInactive(e) => write!(f, "destination left inactive, re-enabling failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DrainError {}
+
+impl From<jsonrpsee::core::Error> for DrainError {
+    fn from(e: jsonrpsee::core::Error) -> Self {
+        DrainError::Rpc(e)
+    }
+}
+
+#[derive(Debug)]
+pub struct DrainReport<T> {
+    pub dialogs_at_start: usize,
+    pub dialogs_at_end: usize,
+    pub timed_out: bool,
+    /// Output of the maintenance future, `None` for a dry run.
+    pub output: Option<T>,
+}
+
+/// Takes one dispatcher destination out of rotation, waits for its calls to finish, runs some
+/// maintenance and puts it back.
+pub struct DispatcherDrain<'a, C> {
+    client: &'a C,
+    partition: Option<String>,
+    group: usize,
+    address: String,
+    dialogs: ActiveDialogs,
+    drain_timeout: Duration,
+    poll_interval: Duration,
+    confirm_timeout: Duration,
+    dry_run: bool,
+    progress: Option<mpsc::Sender<DrainProgress>>,
+    status_events: Option<mpsc::Receiver<DispatcherStatus>>,
+}
+
+impl<'a, C> DispatcherDrain<'a, C>
+    where C: OpenSIPSClient + Sync
+{
+    pub fn new(client: &'a C, group: usize, address: impl Into<String>) -> Self {
+        DispatcherDrain {
+            client,
+            partition: None,
+            group,
+            address: address.into(),
+            dialogs: ActiveDialogs::Ignore,
+            drain_timeout: Duration::from_secs(300),
+            poll_interval: Duration::from_secs(5),
+            confirm_timeout: Duration::from_secs(30),
+            dry_run: false,
+            progress: None,
+            status_events: None,
+        }
+    }
+
+    pub fn partition(mut self, partition: impl Into<String>) -> Self {
+        self.partition = Some(partition.into());
+        self
+    }
+
+    pub fn dialogs(mut self, dialogs: ActiveDialogs) -> Self {
+        self.dialogs = dialogs;
+        self
+    }
+
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    pub fn confirm_timeout(mut self, confirm_timeout: Duration) -> Self {
+        self.confirm_timeout = confirm_timeout;
+        self
+    }
+
+    /// Only report the current state and dialog count, nothing is changed and maintenance is not run.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn progress(mut self, progress: mpsc::Sender<DrainProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// E_DISPATCHER_STATUS notifications; when given, re-enabling also waits for the matching event.
+    pub fn status_events(mut self, status_events: mpsc::Receiver<DispatcherStatus>) -> Self {
+        self.status_events = Some(status_events);
+        self
+    }
+
+    pub async fn run<F>(mut self, maintenance: F) -> Result<DrainReport<F::Output>, DrainError>
+        where F: Future
+    {
+        let state = self.destination_state().await?
+            .ok_or(DrainError::DestinationNotFound)?;
+        let dialogs_at_start = self.active_dialogs().await?;
+
+        if self.dry_run {
+            self.report(DrainProgress::DryRun { state, active_dialogs: dialogs_at_start }).await;
+            return Ok(DrainReport {
+                dialogs_at_start,
+                dialogs_at_end: dialogs_at_start,
+                timed_out: false,
+                output: None,
+            });
+        }
+
+        self.set_state(SetState::Inactive).await?;
+        self.report(DrainProgress::Disabled).await;
+
+        let (active_dialogs, timed_out) = match self.wait_for_dialogs(dialogs_at_start).await {
+            Ok(drained) => drained,
+            Err(e) => {
+                warn!("drain {}/{} failed, re-enabling: {}", self.group, self.address, e);
+                if let Err(enable) = self.enable().await {
+                    warn!("drain {}/{}: {}", self.group, self.address, enable);
+                }
+                return Err(e);
+            }
+        };
+        if timed_out {
+            self.report(DrainProgress::TimedOut { active_dialogs }).await;
+        } else {
+            self.report(DrainProgress::Drained).await;
+        }
+
+        self.report(DrainProgress::MaintenanceStarted).await;
+        let output = maintenance.await;
+        self.report(DrainProgress::MaintenanceFinished).await;
+
+        self.enable().await?;
+        self.confirm().await?;
+        self.report(DrainProgress::Confirmed).await;
+
+        Ok(DrainReport {
+            dialogs_at_start,
+            dialogs_at_end: active_dialogs,
+            timed_out,
+            output: Some(output),
+        })
+    }
+
+    // returns the dialogs left and whether the drain timed out
+    async fn wait_for_dialogs(&self, mut active_dialogs: usize) -> Result<(usize, bool), DrainError> {
+        let deadline = Instant::now() + self.drain_timeout;
+        while active_dialogs > 0 {
+            if Instant::now() >= deadline {
+                return Ok((active_dialogs, true));
+            }
+            self.report(DrainProgress::Waiting { active_dialogs }).await;
+            sleep(self.poll_interval).await;
+            active_dialogs = self.active_dialogs().await?;
+        }
+        Ok((0, false))
+    }
+
+    async fn enable(&self) -> Result<(), DrainError> {
+        if let Err(e) = self.set_state(SetState::Active).await {
+            return Err(DrainError::LeftInactive(Box::new(e)));
+        }
+        self.report(DrainProgress::Enabled).await;
+        Ok(())
+    }
+
+    async fn report(&self, progress: DrainProgress) {
+        debug!("drain {}/{}: {:?}", self.group, self.address, progress);
+        if let Some(tx) = &self.progress {
+            let _ = tx.send(progress).await;
+        }
+    }
+
+    async fn set_state(&self, state: SetState) -> Result<(), DrainError> {
+        match &self.partition {
+            Some(partition) => self.client.ds_set_state_partition(state, self.group, self.address.clone(), partition.clone()).await?,
+            None => self.client.ds_set_state(state, self.group, self.address.clone()).await?,
+        };
+        Ok(())
+    }
+
+    async fn destination_state(&self) -> Result<Option<DestinationState>, DrainError> {
+        let partition = self.partition.as_deref().unwrap_or("default");
+        let list = self.client.ds_list(0).await?;
+        Ok(list.partitions.iter()
+            .filter(|p| p.name == partition)
+            .flat_map(|p| p.sets.iter())
+            .filter(|set| set.id == self.group)
+            .flat_map(|set| set.destinations.iter())
+            .find(|d| d.uri == self.address)
+            .map(|d| d.state.clone()))
+    }
+
+    async fn active_dialogs(&self) -> Result<usize, DrainError> {
+        Ok(match &self.dialogs {
+            ActiveDialogs::Ignore => 0,
+            ActiveDialogs::Profile { profile, value: Some(value) } =>
+                self.client.profile_get_size_value(profile.clone(), value.clone()).await?.profile.count,
+            ActiveDialogs::Profile { profile, value: None } =>
+                self.client.profile_get_size(profile.clone()).await?.profile.count,
+            ActiveDialogs::Filter(filter) => self.client.dlg_list().await?
+                .dialogs
+                .iter()
+                .filter(|d| d.state != DialogState::Deleted && filter(d))
+                .count(),
+        })
+    }
+
+    async fn confirm(&mut self) -> Result<(), DrainError> {
+        if let Some(mut events) = self.status_events.take() {
+            let partition = self.partition.as_deref().unwrap_or("default");
+            let group = self.group.to_string();
+            let address = self.address.clone();
+            let seen = timeout(self.confirm_timeout, async {
+                while let Some(status) = events.recv().await {
+                    if status.partition == partition && status.group == group && status.address == address
+                        && status.status == DispatcherState::Active
+                    {
+                        return true;
+                    }
+                }
+                false
+            }).await;
+            if !matches!(seen, Ok(true)) {
+                return Err(DrainError::NotConfirmed(self.destination_state().await?));
+            }
+        }
+        match self.destination_state().await? {
+            Some(DestinationState::Active) => Ok(()),
+            state => Err(DrainError::NotConfirmed(state)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use serde_json::{json, Value};
+    use crate::mock::{MockError, MockOpenSIPS};
+
+    const ADDRESS: &str = "sip:10.0.0.1:5060";
+
+    // ds_list follows ds_set_state, and each profile_get_size takes the next count
+    fn destination(mock: &MockOpenSIPS, counts: Vec<usize>) {
+        let state = Arc::new(Mutex::new("Active"));
+        let listed = state.clone();
+        mock.respond_with("ds_list", move |_| Ok(json!({"PARTITIONS": [{"name": "default", "SETS": [{"id": 1,
+            "Destinations": [{"URI": ADDRESS, "state": *listed.lock().unwrap(), "resolved_addresses": []}]}]}]})));
+        mock.respond_with("ds_set_state", move |params| {
+            *state.lock().unwrap() = if params["state"] == "a" { "Active" } else { "Inactive" };
+            Ok(json!("OK"))
+        });
+        let counts = Mutex::new(counts.into_iter());
+        mock.respond_with("profile_get_size", move |_| match counts.lock().unwrap().next() {
+            Some(count) => Ok(json!({"Profile": {"name": "gw", "count": count}})),
+            None => Err(MockError::new(500, "Internal error")),
+        });
+    }
+
+    fn states(mock: &MockOpenSIPS) -> Vec<Value> {
+        mock.calls("ds_set_state").into_iter().map(|p| p["state"].clone()).collect()
+    }
+
+    fn status(partition: &str) -> DispatcherStatus {
+        DispatcherStatus { partition: partition.into(), group: "1".into(), address: ADDRESS.into(), status: DispatcherState::Active }
+    }
+
+    fn drain(client: &jsonrpsee::http_client::HttpClient) -> DispatcherDrain<'_, jsonrpsee::http_client::HttpClient> {
+        DispatcherDrain::new(client, 1, ADDRESS)
+            .dialogs(ActiveDialogs::Profile { profile: "gw".into(), value: None })
+            .poll_interval(Duration::from_millis(5))
+            .confirm_timeout(Duration::from_millis(100))
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        destination(&mock, vec![2, 1, 0]);
+        let client = mock.client();
+        let (events, status_events) = mpsc::channel(4);
+        // only the event from the destination's own partition confirms it
+        events.send(status("other")).await.unwrap();
+        let confirmed = tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            events.send(status("default")).await.unwrap();
+        });
+        let (progress, mut updates) = mpsc::channel(32);
+
+        let started = Instant::now();
+        let report = drain(&client).status_events(status_events).progress(progress).run(async { 42 }).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        confirmed.await.unwrap();
+        assert_eq!((report.dialogs_at_start, report.dialogs_at_end, report.timed_out, report.output), (2, 0, false, Some(42)));
+        assert_eq!(states(&mock), vec![json!("i"), json!("a")]);
+        let mut seen = Vec::new();
+        while let Ok(update) = updates.try_recv() {
+            seen.push(update);
+        }
+        assert_eq!(seen.first(), Some(&DrainProgress::Disabled));
+        assert_eq!(seen.last(), Some(&DrainProgress::Confirmed));
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        destination(&mock, vec![3; 100]);
+        let client = mock.client();
+        let report = drain(&client).drain_timeout(Duration::from_millis(20)).run(async {}).await.unwrap();
+        assert!(report.timed_out);
+        assert_eq!(report.dialogs_at_end, 3);
+        assert_eq!(states(&mock), vec![json!("i"), json!("a")]);
+    }
+
+    #[tokio::test]
+    async fn test_drain_failure_re_enables() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        // the count fails on the second poll
+        destination(&mock, vec![2, 1]);
+        let client = mock.client();
+        assert!(matches!(drain(&client).run(async {}).await, Err(DrainError::Rpc(_))));
+        assert_eq!(states(&mock), vec![json!("i"), json!("a")]);
+
+        // when putting it back fails too, the drain's own failure is still the one reported
+        destination(&mock, vec![2, 1]);
+        mock.respond_with("ds_set_state", |params| match params["state"].as_str() {
+            Some("i") => Ok(json!("OK")),
+            _ => Err(MockError::new(503, "Busy")),
+        });
+        match drain(&client).run(async {}).await {
+            Err(DrainError::Rpc(e)) => assert!(e.to_string().contains("Internal error"), "{}", e),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -1,11 +1,19 @@
+use std::fmt;
 use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+use serde::Deserialize as _;
 use serde_derive::{Serialize, Deserialize};
 use serde_repr::*;
 use tokio::net::UdpSocket;
-use tracing::debug;
+use tokio::sync::mpsc;
+use tracing::{debug, debug_span, info_span, Instrument, Span};
 use super::*;
+use super::metrics::ReceiverMetrics;
+use super::recording::Recorder;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UlContact {
     pub domain: String,
     pub aor: String,
@@ -69,6 +77,23 @@ pub struct ClustererNodeStateChange {
     pub new_state: ClusterNodeState,
 }
 
+// E_CLUSTERER_RPL_RECEIVED carries no tag
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClustererMessage {
+    pub cluster_id: usize,
+    pub src_id: usize,
+    pub msg: String,
+    #[serde(default)]
+    pub tag: String,
+}
+
+impl ClustererMessage {
+    /// Decodes a message sent with `clusterer::ClusterBus`.
+    pub fn decode<T: serde::de::DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(&self.msg)
+    }
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all="SCREAMING_SNAKE_CASE")]
 pub enum UAEventType {
@@ -93,8 +118,33 @@ pub struct UASession {
     pub headers: String,
 }
 
+impl UASession {
+    pub fn sip_headers(&self) -> sip::SipHeaders {
+        sip::SipHeaders::parse(&self.headers)
+    }
+
+    /// The event as a SIP message: a reply when it carries a status, otherwise a request
+    /// whose Request-URI is left empty, as the event doesn't include it.
+    pub fn sip_message(&self) -> sip::SipMessage {
+        let start_line = if self.status > 0 {
+            sip::StartLine::Response { version: "SIP/2.0".to_string(), code: self.status as u16, reason: self.reason.clone() }
+        } else {
+            sip::StartLine::Request { method: self.method.clone(), uri: String::new(), version: "SIP/2.0".to_string() }
+        };
+        sip::SipMessage { start_line, headers: self.sip_headers(), body: self.body.clone() }
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
+    /// The body as SDP, when the session says it is one.
+    pub fn sdp(&self) -> Result<Option<sdp::Sdp>, sdp::SdpError> {
+        if !self.sip_headers().get("Content-Type").is_some_and(sdp::is_sdp) || self.body.is_empty() {
+            return Ok(None);
+        }
+        self.body.parse().map(Some)
+    }
+}
+
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "method", content = "params", rename_all="SCREAMING_SNAKE_CASE")]
 pub enum Notification {
     EUlContactInsert(UlContact),
@@ -103,19 +153,143 @@ pub enum Notification {
     EDlgStateChanged(DialogChange),
     EDispatcherStatus(DispatcherStatus),
     EClustererNodeStateChange(ClustererNodeStateChange),
+    EClustererReqReceived(ClustererMessage),
+    EClustererRplReceived(ClustererMessage),
     EUaSession(UASession),
 }
 
+impl Notification {
+    /// The OpenSIPS event name, as used with `event_subscribe`.
+    pub fn event_name(&self) -> &'static str {
+        match self {
+            Notification::EUlContactInsert(_) => "E_UL_CONTACT_INSERT",
+            Notification::EUlContactDelete(_) => "E_UL_CONTACT_DELETE",
+            Notification::EUlContactUpdate(_) => "E_UL_CONTACT_UPDATE",
+            Notification::EDlgStateChanged(_) => "E_DLG_STATE_CHANGED",
+            Notification::EDispatcherStatus(_) => "E_DISPATCHER_STATUS",
+            Notification::EClustererNodeStateChange(_) => "E_CLUSTERER_NODE_STATE_CHANGE",
+            Notification::EClustererReqReceived(_) => "E_CLUSTERER_REQ_RECEIVED",
+            Notification::EClustererRplReceived(_) => "E_CLUSTERER_RPL_RECEIVED",
+            Notification::EUaSession(_) => "E_UA_SESSION",
+        }
+    }
+}
+
+
+/// A datagram that couldn't be decoded, with the raw payload.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub source: SocketAddr,
+    pub received_at: SystemTime,
+    pub payload: Vec<u8>,
+    pub error: serde_json::Error,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error decoding {} bytes from {}: {}", self.payload.len(), self.source, self.error)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// The part of a datagram that didn't decode: all of it, or one element of a batch.
+#[derive(Debug)]
+pub struct PayloadError {
+    pub payload: Vec<u8>,
+    pub error: serde_json::Error,
+}
+
+/// Decodes a datagram holding either one notification or a JSON-RPC batch of them. The
+/// elements of a batch are decoded one by one, so a bad one doesn't cost the others.
+pub fn decode_payload(payload: &[u8]) -> Vec<Result<Notification, PayloadError>> {
+    let whole = |error| vec![Err(PayloadError { payload: payload.to_vec(), error })];
+    match payload.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'[') => match serde_json::from_slice::<Vec<serde_json::Value>>(payload) {
+            Ok(batch) => batch.iter()
+                .map(|element| Notification::deserialize(element)
+                    .map_err(|error| PayloadError { payload: element.to_string().into_bytes(), error }))
+                .collect(),
+            Err(error) => whole(error),
+        },
+        _ => match serde_json::from_slice(payload) {
+            Ok(notification) => vec![Ok(notification)],
+            Err(error) => whole(error),
+        },
+    }
+}
 
 pub struct UdpNotificationReceiver {
     pub socket: UdpSocket,
+    pub(crate) allowed_sources: Option<Vec<IpAddr>>,
+    pub(crate) errors: Option<mpsc::Sender<DecodeError>>,
+    pub(crate) metrics: Arc<ReceiverMetrics>,
+    pub(crate) recorder: Option<Recorder>,
+}
+
+impl From<UdpSocket> for UdpNotificationReceiver {
+    fn from(socket: UdpSocket) -> Self {
+        UdpNotificationReceiver::new(socket)
+    }
 }
 
 impl UdpNotificationReceiver {
+    pub fn new(socket: UdpSocket) -> Self {
+        UdpNotificationReceiver { socket, allowed_sources: None, errors: None, metrics: Default::default(), recorder: None }
+    }
+
+    /// Ignore datagrams from any other address.
+    pub fn allowed_sources(mut self, sources: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.allowed_sources = Some(sources.into_iter().collect());
+        self
+    }
+
+    /// Where undecodable datagrams go; without it they are only logged.
+    pub fn errors(mut self, errors: mpsc::Sender<DecodeError>) -> Self {
+        self.errors = Some(errors);
+        self
+    }
+
+    /// Counts into `metrics`, e.g. to read them while the receiver runs or to share them with
+    /// other receivers.
+    pub fn metrics(mut self, metrics: Arc<ReceiverMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Records every accepted datagram, before decoding, e.g. to replay it later.
+    pub fn recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub(crate) fn accepts(allowed_sources: &Option<Vec<IpAddr>>, source: &SocketAddr) -> bool {
+        match allowed_sources {
+            Some(allowed) => allowed.contains(&source.ip()),
+            None => true,
+        }
+    }
+
     pub async fn run<F>(self, f: impl Fn(Notification) -> F) -> tokio::io::Result<()>
       where F: Future<Output = ()>
     {
-        let UdpNotificationReceiver { socket, .. } = self;
+        let metrics = self.metrics.clone();
+        self.receive(|notification, span| {
+            let metrics = metrics.clone();
+            let handled = f(notification).instrument(span);
+            async move {
+                let started = Instant::now();
+                handled.await;
+                metrics.record_handler(started.elapsed());
+            }
+        }).await
+    }
+
+    // the socket loop behind `run`, handing each notification over with its span
+    pub(crate) async fn receive<F>(self, f: impl Fn(Notification, Span) -> F) -> tokio::io::Result<()>
+      where F: Future<Output = ()>
+    {
+        let UdpNotificationReceiver { socket, allowed_sources, errors, metrics, recorder } = self;
         let mut buf = vec![0u8; 65536];
 
         debug!("starting UDP receiver loop");
@@ -123,20 +297,47 @@ impl UdpNotificationReceiver {
             let (size, source) = socket
                 .recv_from(&mut buf)
                 .await?;
+            metrics.record_datagram();
+            let span = debug_span!("datagram", source = %source, size);
+            let entered = span.enter();
             debug!("received packet {} bytes from {:?}", size, source);
-            let decode_result = serde_json::from_slice(&buf[0..size]);
-            match decode_result {
-                Ok(notification) => {
-                    debug!("received: {:?}", notification);
-                    f(notification).await;
+            if !Self::accepts(&allowed_sources, &source) {
+                debug!("ignoring packet from {:?}", source);
+                metrics.record_rejected();
+                continue;
+            }
+            drop(entered);
+            if let Some(recorder) = &recorder {
+                if let Err(e) = recorder.record_payload(Some(source), &buf[0..size]).await {
+                    debug!("failed to record datagram: {:?}", e);
+                }
+            }
+            let decoded = decode_payload(&buf[0..size]);
+            for result in decoded {
+                match result {
+                    Ok(notification) => {
+                        let method = notification.event_name();
+                        metrics.record_notification(method);
+                        let span = info_span!("notification", method, source = %source);
+                        span.in_scope(|| debug!("received: {:?}", notification));
+                        f(notification, span).await;
+                    }
+                    Err(PayloadError { payload, error }) => {
+                        debug!("error decoding: {:?}", error);
+                        metrics.record_decode_error(&payload);
+                        if let Some(errors) = &errors {
+                            let error = DecodeError { source, received_at: SystemTime::now(), payload, error };
+                            if errors.try_send(error).is_err() {
+                                debug!("decode error channel full or closed");
+                            }
+                        }
+                    }
                 }
-                Err(e) => debug!("error decoding: {:?}", e)
             }
         }
     }
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -151,6 +352,62 @@ mod tests {
         let json: Vec<Notification> = serde_json::from_str(input).unwrap();
         println!("{:#?}", json);
     }
-}
 
+    #[test]
+    fn test_decode_payload() {
+        let single = r#"{"jsonrpc":"2.0","method":"E_CLUSTERER_NODE_STATE_CHANGE","params":{"cluster_id":1,"node_id":2,"new_state":0}}"#;
+        let batch = format!("[{}, {{\"jsonrpc\":\"2.0\",\"method\":\"E_UNKNOWN\",\"params\":{{}}}}, {}]", single, single);
+        let decoded = decode_payload(batch.as_bytes());
+        assert_eq!(decoded.iter().map(Result::is_ok).collect::<Vec<_>>(), vec![true, false, true]);
+        assert_eq!(decode_payload(single.as_bytes()).len(), 1);
+        assert_eq!(decode_payload(b" [1").len(), 1);
+    }
 
+    #[test]
+    fn test_clusterer_message_decode() {
+        let input = r#"{"jsonrpc":"2.0","method":"E_CLUSTERER_REQ_RECEIVED","params":{"cluster_id":1,"src_id":2,"msg":"{\"drain\":true}","tag":"ctl"}}"#;
+        let notification: Notification = serde_json::from_str(input).unwrap();
+        match notification {
+            Notification::EClustererReqReceived(m) => {
+                assert_eq!(m.src_id, 2);
+                assert_eq!(m.tag, "ctl");
+                let body: serde_json::Value = m.decode().unwrap();
+                assert_eq!(body["drain"], true);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receiver_batch_and_errors() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (errors_tx, mut errors) = mpsc::channel(2);
+        let (tx, mut rx) = mpsc::channel(4);
+        let receiver = UdpNotificationReceiver::new(socket)
+            .allowed_sources(["127.0.0.1".parse().unwrap()])
+            .errors(errors_tx);
+        let metrics = receiver.metrics.clone();
+        tokio::spawn(receiver.run(move |n| {
+            let tx = tx.clone();
+            async move {
+                let _ = tx.send(n).await;
+            }
+        }));
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.send_to(b"{not json", addr).await.unwrap();
+        // the unknown event only costs itself
+        let batch = r#"[{"jsonrpc":"2.0","method":"E_CLUSTERER_NODE_STATE_CHANGE","params":{"cluster_id":1,"node_id":2,"new_state":0}},
+            {"jsonrpc":"2.0","method":"E_UNKNOWN","params":{}},
+            {"jsonrpc":"2.0","method":"E_CLUSTERER_NODE_STATE_CHANGE","params":{"cluster_id":1,"node_id":3,"new_state":1}}]"#;
+        sender.send_to(batch.as_bytes(), addr).await.unwrap();
+
+        assert_eq!(errors.recv().await.unwrap().payload, b"{not json");
+        let element: serde_json::Value = serde_json::from_slice(&errors.recv().await.unwrap().payload).unwrap();
+        assert_eq!(element["method"], "E_UNKNOWN");
+        assert_eq!(metrics.snapshot().decode_errors.into_iter().collect::<Vec<_>>(), vec![("<invalid>".to_string(), 1), ("E_UNKNOWN".to_string(), 1)]);
+        assert_eq!(rx.recv().await.unwrap().event_name(), "E_CLUSTERER_NODE_STATE_CHANGE");
+        assert!(matches!(rx.recv().await, Some(Notification::EClustererNodeStateChange(ClustererNodeStateChange { node_id: 3, .. }))));
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::hash_map::RandomState;
+use std::ffi::CString;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use jsonrpsee::core::{async_trait, Error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::pipe;
+use tokio::sync::Mutex;
+use super::transport::{MiClient, MiTransport};
+
+/// Talks to OpenSIPS' `mi_fifo` module.
+///
+/// Each request gets its own reply FIFO in `reply_dir`, which must match the module's
+/// `reply_dir` parameter (`/tmp/` by default).
+pub struct FifoTransport {
+    fifo: PathBuf,
+    reply_dir: PathBuf,
+    // writes bigger than PIPE_BUF aren't atomic, so keep requests from interleaving
+    write_lock: Mutex<()>,
+}
+
+pub type FifoMiClient = MiClient<FifoTransport>;
+
+impl FifoMiClient {
+    pub fn open(fifo: impl Into<PathBuf>) -> Self {
+        MiClient::new(FifoTransport::new(fifo))
+    }
+}
+
+impl FifoTransport {
+    pub fn new(fifo: impl Into<PathBuf>) -> Self {
+        FifoTransport {
+            fifo: fifo.into(),
+            reply_dir: PathBuf::from("/tmp"),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn reply_dir(mut self, reply_dir: impl Into<PathBuf>) -> Self {
+        self.reply_dir = reply_dir.into();
+        self
+    }
+
+    async fn write_request(&self, reply_name: Option<&str>, request: &str) -> io::Result<()> {
+        let _guard = self.write_lock.lock().await;
+        let mut tx = pipe::OpenOptions::new().open_sender(&self.fifo)?;
+        let line = format!(":{}:{}\n", reply_name.unwrap_or(""), request);
+        tx.write_all(line.as_bytes()).await
+    }
+}
+
+/// A reply FIFO that is removed when dropped.
+struct ReplyFifo {
+    path: PathBuf,
+}
+
+impl ReplyFifo {
+    // the name can't be guessed ahead of time, and mkfifo fails rather than reuse a file
+    // someone else created
+    fn create(dir: &Path) -> io::Result<(Self, String)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+        let name = format!("opensips_reply_{}_{:016x}", std::process::id(), hasher.finish());
+        let path = dir.join(&name);
+        // OpenSIPS usually runs as another user and has to be able to open it for writing, but
+        // nobody else gets to read the reply
+        mkfifo(&path, 0o622)?;
+        Ok((ReplyFifo { path }, name))
+    }
+}
+
+impl Drop for ReplyFifo {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub(crate) fn mkfifo(path: &Path, mode: u32) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), mode as libc::mode_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // mkfifo is subject to the umask
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(mode))
+}
+
+// Reads until the data parses as one complete JSON document.
+async fn read_reply(path: &Path) -> io::Result<Vec<u8>> {
+    let mut options = pipe::OpenOptions::new();
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    options.read_write(true);
+    let mut rx = options.open_receiver(path)?;
+    // where the FIFO can't be opened read-write, holding a writer open has the same effect:
+    // reads wait for OpenSIPS instead of returning EOF until it opens the FIFO
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _writer = pipe::OpenOptions::new().open_sender(path)?;
+
+    let mut reply = Vec::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let size = rx.read(&mut buf).await?;
+        if size == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "reply FIFO closed before a complete reply"));
+        }
+        reply.extend_from_slice(&buf[..size]);
+        if serde_json::from_slice::<serde::de::IgnoredAny>(&reply).is_ok() {
+            return Ok(reply);
+        }
+    }
+}
+
+#[async_trait]
+impl MiTransport for FifoTransport {
+    async fn round_trip(&self, request: String, _ids: &[u64]) -> Result<Vec<u8>, Error> {
+        let transport = |e: io::Error| Error::Transport(e.into());
+        let (reply_fifo, reply_name) = ReplyFifo::create(&self.reply_dir).map_err(transport)?;
+        let reply = read_reply(&reply_fifo.path);
+        tokio::pin!(reply);
+
+        // open the reply FIFO before OpenSIPS tries to write to it
+        tokio::select! {
+            biased;
+            result = &mut reply => return result.map_err(transport),
+            result = self.write_request(Some(&reply_name), &request) => result.map_err(transport)?,
+        }
+        reply.await.map_err(transport)
+    }
+
+    async fn send(&self, notification: String) -> Result<(), Error> {
+        self.write_request(None, &notification).await
+            .map_err(|e| Error::Transport(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+    use crate::OpenSIPSClient;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn test_fifo_version() {
+        let dir = std::env::temp_dir().join(format!("opensips-fifo-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let fifo = dir.join("opensips_fifo");
+        let _ = std::fs::remove_file(&fifo);
+        mkfifo(&fifo, 0o600).unwrap();
+
+        // stands in for the mi_fifo reader process
+        let rx = pipe::OpenOptions::new().read_write(true).open_receiver(&fifo).unwrap();
+        let server_dir = dir.clone();
+        let server = tokio::spawn(async move {
+            let mut line = String::new();
+            tokio::io::BufReader::new(rx).read_line(&mut line).await.unwrap();
+            let (reply_name, request) = line.trim_end()[1..].split_once(':').unwrap();
+            let request: serde_json::Value = serde_json::from_str(request).unwrap();
+            assert_eq!(request["method"], "version");
+            let reply = serde_json::json!({"jsonrpc": "2.0", "id": request["id"], "result": {"Server": "OpenSIPS (3.4.0 (x86_64/linux))"}});
+            let reply_path = server_dir.join(reply_name);
+            let mode = std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&reply_path).unwrap().permissions());
+            assert_eq!(mode & 0o777, 0o622);
+            let mut tx = pipe::OpenOptions::new().open_sender(&reply_path).unwrap();
+            tx.write_all(&serde_json::to_vec(&reply).unwrap()).await.unwrap();
+            reply_path
+        });
+
+        let client = FifoMiClient::new(FifoTransport::new(&fifo).reply_dir(&dir));
+        assert_eq!(client.version().await.unwrap().server, "OpenSIPS (3.4.0 (x86_64/linux))");
+        assert!(!server.await.unwrap().exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use futures::future::join_all;
+use tokio::time::timeout;
+use super::*;
+
+#[derive(Debug)]
+pub enum FleetError {
+    Rpc(jsonrpsee::core::Error),
+    Timeout,
+}
+
+impl fmt::Display for FleetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FleetError::Rpc(e) => write!(f, "MI error: {}", e),
+            FleetError::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl std::error::Error for FleetError {}
+
+impl From<jsonrpsee::core::Error> for FleetError {
+    fn from(e: jsonrpsee::core::Error) -> Self {
+        FleetError::Rpc(e)
+    }
+}
+
+/// A value merged from the nodes that answered, along with the errors of those that didn't.
+#[derive(Debug, Default)]
+pub struct FleetMerge<T> {
+    pub value: T,
+    pub errors: BTreeMap<String, FleetError>,
+}
+
+/// A set of named OpenSIPS nodes that can be queried together.
+pub struct OpenSIPSFleet<C> {
+    nodes: BTreeMap<String, C>,
+    timeout: Duration,
+}
+
+impl<C> OpenSIPSFleet<C> {
+    pub fn new(timeout: Duration) -> Self {
+        OpenSIPSFleet { nodes: BTreeMap::new(), timeout }
+    }
+
+    pub fn with_node(mut self, name: impl Into<String>, client: C) -> Self {
+        self.insert(name, client);
+        self
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, client: C) -> Option<C> {
+        self.nodes.insert(name.into(), client)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<C> {
+        self.nodes.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&C> {
+        self.nodes.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.nodes.keys()
+    }
+
+    pub fn into_nodes(self) -> Vec<(String, C)> {
+        self.nodes.into_iter().collect()
+    }
+
+    /// Runs `f` against every node at once, each call bounded by the fleet timeout.
+    pub async fn call<'a, T, F, Fut>(&'a self, f: F) -> BTreeMap<String, Result<T, FleetError>>
+        where F: Fn(&'a C) -> Fut,
+              Fut: Future<Output = Result<T, jsonrpsee::core::Error>>
+    {
+        let calls = self.nodes.iter().map(|(name, client)| {
+            let call = f(client);
+            async move {
+                let result = match timeout(self.timeout, call).await {
+                    Ok(result) => result.map_err(FleetError::from),
+                    Err(_) => Err(FleetError::Timeout),
+                };
+                (name.clone(), result)
+            }
+        });
+        join_all(calls).await.into_iter().collect()
+    }
+
+    fn merge<T, V, M>(results: BTreeMap<String, Result<T, FleetError>>, mut value: V, mut merge: M) -> FleetMerge<V>
+        where M: FnMut(&mut V, T)
+    {
+        let mut errors = BTreeMap::new();
+        for (name, result) in results {
+            match result {
+                Ok(r) => merge(&mut value, r),
+                Err(e) => {
+                    errors.insert(name, e);
+                }
+            }
+        }
+        FleetMerge { value, errors }
+    }
+}
+
+impl<C> OpenSIPSFleet<C>
+    where C: OpenSIPSClient + Sync
+{
+    /// Every AOR registered on any node.
+    pub async fn ul_aors(&self) -> FleetMerge<BTreeSet<String>> {
+        let results = self.call(|c| c.ul_dump()).await;
+        Self::merge(results, BTreeSet::new(), |aors, dump| {
+            aors.extend(dump.domains.into_iter()
+                .flat_map(|d| d.aors)
+                .map(|a| a.aor));
+        })
+    }
+
+    /// Number of dialogs across all nodes; replicated dialogs are counted on every node holding them.
+    pub async fn dialog_count(&self) -> FleetMerge<usize> {
+        let results = self.call(|c| c.dlg_list()).await;
+        Self::merge(results, 0, |count, list| *count += list.dialogs.len())
+    }
+
+    pub async fn ds_list(&self, full: usize) -> BTreeMap<String, Result<dispatcher::ListResponse, FleetError>> {
+        self.call(|c| c.ds_list(full)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use crate::mock::{MockError, MockOpenSIPS};
+
+    fn aors(names: &[&str]) -> Value {
+        json!({"Domains": [{"AORs": names.iter().map(|aor| json!({"AOR": aor, "Contacts": []})).collect::<Vec<_>>()}]})
+    }
+
+    fn dialogs(count: usize) -> Value {
+        let dialog = json!({"ID": "1", "callid": "c", "state": 4, "timestart": 0, "from_uri": "", "to_uri": "", "caller_tag": ""});
+        json!({"Dialogs": vec![dialog; count]})
+    }
+
+    #[tokio::test]
+    async fn test_fan_out_and_merge() {
+        let (a, b, slow) = (MockOpenSIPS::start().await.unwrap(), MockOpenSIPS::start().await.unwrap(), MockOpenSIPS::start().await.unwrap());
+        a.respond("ul_dump", aors(&["alice@example.com", "bob@example.com"]));
+        a.respond("dlg_list", dialogs(2));
+        b.respond("ul_dump", aors(&["bob@example.com", "carol@example.com"]));
+        b.fail("dlg_list", MockError::new(500, "Internal error"));
+        slow.respond("ul_dump", aors(&["dave@example.com"]));
+        slow.respond("dlg_list", dialogs(5));
+        slow.delay("ul_dump", Duration::from_millis(500));
+        slow.delay("dlg_list", Duration::from_millis(500));
+        let fleet = OpenSIPSFleet::new(Duration::from_millis(100))
+            .with_node("a", a.client())
+            .with_node("b", b.client())
+            .with_node("slow", slow.client());
+
+        let merged = fleet.ul_aors().await;
+        assert_eq!(merged.value.into_iter().collect::<Vec<_>>(), vec!["alice@example.com", "bob@example.com", "carol@example.com"]);
+        assert_eq!(merged.errors.keys().collect::<Vec<_>>(), vec!["slow"]);
+        assert!(matches!(merged.errors["slow"], FleetError::Timeout));
+
+        let merged = fleet.dialog_count().await;
+        assert_eq!(merged.value, 2);
+        assert!(matches!(merged.errors["b"], FleetError::Rpc(_)));
+        assert!(matches!(merged.errors["slow"], FleetError::Timeout));
+    }
+}
@@ -4,118 +4,192 @@ use serde_repr::*;
 
 pub mod events;
 pub use events::*;
+pub mod stream;
+pub mod dispatch;
+pub mod metrics;
+pub mod spool;
+pub mod dedup;
+pub mod webhook;
+pub mod ua_session;
+pub mod sip;
+pub mod sdp;
+pub mod click_to_call;
+pub mod registrant;
+pub mod router;
+pub mod drain;
+pub mod topology;
+pub mod fleet;
+pub mod transport;
+pub mod datagram;
+#[cfg(unix)]
+pub mod fifo;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod recording;
 
-#[rpc(client)]
-pub trait OpenSIPS {
-    /// core methods
-    #[method(name="version",param_kind=map)]
-    fn version(&self) -> Result<VersionResponse, Error>;
-
-    // log_level has 3 variants:
-    #[method(name="log_level",param_kind=map)]
-    fn get_log_level(&self) -> Result<LogLevelResponse, Error>;
-    #[method(name="log_level",param_kind=map)]
-    fn set_log_level(&self, level: LogLevel) -> Result<LogLevelResponse, Error>;
-    #[method(name="log_level",param_kind=map)]
-    fn set_log_level_pid(&self, level: LogLevel, pid: usize) -> Result<LogLevelResponse, Error>;
-
-    // xlog_level has 2 variants:
-    #[method(name="xlog_level",param_kind=map)]
-    fn get_xlog_level(&self) -> Result<XLogLevelResponse, Error>;
-    #[method(name="xlog_level",param_kind=map)]
-    fn set_xlog_level(&self, level: LogLevel) -> Result<XLogLevelResponse, Error>;
-
-    #[method(name="reload_routes")]
-    fn reload_routes(&self) -> Result<String, Error>;
-
-    // cache
-    #[method(name="cache_fetch",param_kind=map)]
-    fn cache_fetch(&self, system: String, attr: String) -> Result<CacheResponse, Error>;
-    #[method(name="cache_store",param_kind=map)]
-    fn cache_store(&self, system: String, attr: String, value: String) -> Result<String, Error>;
-    #[method(name="cache_store",param_kind=map)]
-    fn cache_store_expires(&self, system: String, attr: String, value: String, expires: usize) -> Result<String, Error>;
-    #[method(name="cache_remove",param_kind=map)]
-    fn cache_remove(&self, system: String, attr: String) -> Result<String, Error>;
-
-    #[method(name="events_list",param_kind=map)]
-    fn events_list(&self) -> Result<EventsListResponse, Error>;
-    #[method(name="event_subscribe",param_kind=map)]
-    fn event_subscribe(&self, event: String, socket: String) -> Result<String, Error>;
-    #[method(name="event_subscribe",param_kind=map)]
-    fn event_subscribe_expire(&self, event: String, socket: String, expire: usize) -> Result<String, Error>;
-
-    // dispatcher methods
-    #[method(name="ds_reload",param_kind=map)]
-    fn ds_reload(&self) -> Result<String, Error>;
-    #[method(name="ds_list",param_kind=map)]
-    fn ds_list(&self, full: usize) -> Result<dispatcher::ListResponse, Error>;
-
-    // clusterer methods
-    #[method(name="clusterer_list",param_kind=map)]
-    fn clusterer_list(&self) -> Result<clusterer::ClustererListResponse, Error>;
-    #[method(name="clusterer_list_shtags",param_kind=map)]
-    fn clusterer_list_shtags(&self) -> Result<Vec<clusterer::SharedTagStatus>, Error>;
-    #[method(name="clusterer_shtag_set_active",param_kind=map)]
-    fn clusterer_shtag_set_active(&self, tag: String) -> Result<String, Error>;
-
-    // usrloc methods
-    #[method(name="ul_dump",param_kind=map)]
-    fn ul_dump(&self) -> Result<usrloc::DumpResponse, Error>;
-    #[method(name="ul_rm",param_kind=map)]
-    fn ul_rm(&self, table_name: String, aor: String) -> Result<String, Error>;
-    #[method(name="ul_rm_contact",param_kind=map)]
-    fn ul_rm_contact(&self, table_name: String, aor: String, contact: String) -> Result<String, Error>;
-    #[method(name="ul_flush")]
-    fn ul_flush(&self) -> Result<String, Error>;
-    #[method(name="ul_cluster_sync")]
-    fn ul_cluster_sync(&self) -> Result<String, Error>;
-
-    // tm methods
-    #[method(name="t_uac_dlg",param_kind=map)]
-    fn t_uac_dlg(&self, method: String, ruri: String, headers: String, next_hop: String, socket: String) -> Result<TUacDlgResponse, Error>;
-    #[method(name="t_uac_dlg",param_kind=map)]
-    fn t_uac_dlg_with_body(&self, method: String, ruri: String, headers: String, next_hop: String, socket: String, body: String) -> Result<TUacDlgResponse, Error>;
-
-    // uac_registrant methods
-    #[method(name="reg_list",param_kind=map)]
-    fn reg_list(&self) -> Result<RegListResponse, Error>;
-    #[method(name="reg_list",param_kind=map)]
-    fn reg_list_record(&self, aor: String, contact: String, registrar: String) -> Result<RegListRecordResponse, Error>;
-    #[method(name="reg_reload",param_kind=map)]
-    fn reg_reload(&self) -> Result<String, Error>;
-    #[method(name="reg_reload",param_kind=map)]
-    fn reg_reload_record(&self, aor: String, contact: String, registrar: String) -> Result<String, Error>;
-    #[method(name="reg_enable",param_kind=map)]
-    fn reg_enable(&self, aor: String, contact: String, registrar: String) -> Result<String, Error>;
-    #[method(name="reg_disable",param_kind=map)]
-    fn reg_disable(&self, aor: String, contact: String, registrar: String) -> Result<String, Error>;
-
-    // dialog module
-    #[method(name="dlg_list",param_kind=map)]
-    fn dlg_list(&self) -> Result<dialog::ListResponse, Error>;
-    #[method(name="dlg_list",param_kind=map)]
-    fn dlg_list_record(&self, callid: String, from_tag: String) -> Result<dialog::ListRecordResponse, Error>;
-
-    // b2b_entities module
-    #[method(name="b2be_list")]
-    fn b2be_list(&self) -> Result<String, Error>;
-    // OpenSIPS doesn't appear to accept `null` for optional parameters, which is what Option would send, so leaving
-    // most of the optional types in every method for now.
-    #[method(name="ua_session_client_start",param_kind=map)]
-    fn ua_session_client_start(&self, ruri: String, to: String, from: String, proxy: String, body: String, extra_headers: Vec<String>, content_type: String, flags: String) -> Result<String, Error>;
-    #[method(name="ua_session_reply",param_kind=map)]
-    fn ua_session_reply(&self, key: String, method: String, code: usize, reason: String, body: String, extra_headers: Vec<String>, content_type: String) -> Result<String, Error>;
-    #[method(name="ua_session_update",param_kind=map)]
-    fn ua_session_update(&self, key: String, method: String, body: String, extra_headers: Vec<String>, content_type: String) -> Result<String, Error>;
-    #[method(name="ua_session_terminate",param_kind=map)]
-    fn ua_session_terminate(&self, key: String, extra_headers: Vec<String>) -> Result<String, Error>;
-    #[method(name="ua_session_list",param_kind=map)]
-    fn ua_session_list(&self) -> Result<Vec<b2b_entities::UASession>, Error>;
-    #[method(name="ua_session_list",param_kind=map)]
-    fn ua_session_list_with_key(&self, key: String) -> Result<b2b_entities::UASession, Error>;
+// the client methods mirror OpenSIPS MI parameter lists, some of which are long
+#[allow(clippy::too_many_arguments)]
+mod api {
+    use super::*;
+
+    #[rpc(client)]
+    pub trait OpenSIPS {
+        /// core methods
+        #[method(name="version",param_kind=map)]
+        fn version(&self) -> Result<VersionResponse, Error>;
+
+        // log_level has 3 variants:
+        #[method(name="log_level",param_kind=map)]
+        fn get_log_level(&self) -> Result<LogLevelResponse, Error>;
+        #[method(name="log_level",param_kind=map)]
+        fn set_log_level(&self, level: LogLevel) -> Result<LogLevelResponse, Error>;
+        #[method(name="log_level",param_kind=map)]
+        fn set_log_level_pid(&self, level: LogLevel, pid: usize) -> Result<LogLevelResponse, Error>;
+
+        // xlog_level has 2 variants:
+        #[method(name="xlog_level",param_kind=map)]
+        fn get_xlog_level(&self) -> Result<XLogLevelResponse, Error>;
+        #[method(name="xlog_level",param_kind=map)]
+        fn set_xlog_level(&self, level: LogLevel) -> Result<XLogLevelResponse, Error>;
+
+        #[method(name="reload_routes")]
+        fn reload_routes(&self) -> Result<String, Error>;
+
+        // cache
+        #[method(name="cache_fetch",param_kind=map)]
+        fn cache_fetch(&self, system: String, attr: String) -> Result<CacheResponse, Error>;
+        #[method(name="cache_store",param_kind=map)]
+        fn cache_store(&self, system: String, attr: String, value: String) -> Result<String, Error>;
+        #[method(name="cache_store",param_kind=map)]
+        fn cache_store_expires(&self, system: String, attr: String, value: String, expires: usize) -> Result<String, Error>;
+        #[method(name="cache_remove",param_kind=map)]
+        fn cache_remove(&self, system: String, attr: String) -> Result<String, Error>;
+
+        #[method(name="events_list",param_kind=map)]
+        fn events_list(&self) -> Result<EventsListResponse, Error>;
+        #[method(name="event_subscribe",param_kind=map)]
+        fn event_subscribe(&self, event: String, socket: String) -> Result<String, Error>;
+        #[method(name="event_subscribe",param_kind=map)]
+        fn event_subscribe_expire(&self, event: String, socket: String, expire: usize) -> Result<String, Error>;
+
+        // dispatcher methods
+        #[method(name="ds_reload",param_kind=map)]
+        fn ds_reload(&self) -> Result<String, Error>;
+        #[method(name="ds_list",param_kind=map)]
+        fn ds_list(&self, full: usize) -> Result<dispatcher::ListResponse, Error>;
+        #[method(name="ds_set_state",param_kind=map)]
+        fn ds_set_state(&self, state: dispatcher::SetState, group: usize, address: String) -> Result<String, Error>;
+        #[method(name="ds_set_state",param_kind=map)]
+        fn ds_set_state_partition(&self, state: dispatcher::SetState, group: usize, address: String, partition: String) -> Result<String, Error>;
+
+        // clusterer methods
+        #[method(name="clusterer_list",param_kind=map)]
+        fn clusterer_list(&self) -> Result<clusterer::ClustererListResponse, Error>;
+        #[method(name="clusterer_list_shtags",param_kind=map)]
+        fn clusterer_list_shtags(&self) -> Result<Vec<clusterer::SharedTagStatus>, Error>;
+        #[method(name="clusterer_shtag_set_active",param_kind=map)]
+        fn clusterer_shtag_set_active(&self, tag: String) -> Result<String, Error>;
+        #[method(name="clusterer_reload")]
+        fn clusterer_reload(&self) -> Result<String, Error>;
+        #[method(name="clusterer_set_status",param_kind=map)]
+        fn clusterer_set_status(&self, cluster_id: usize, status: clusterer::Status) -> Result<String, Error>;
+        #[method(name="clusterer_set_status",param_kind=map)]
+        fn clusterer_set_status_node(&self, cluster_id: usize, node_id: usize, status: clusterer::Status) -> Result<String, Error>;
+        #[method(name="clusterer_list_topology",param_kind=map)]
+        fn clusterer_list_topology(&self) -> Result<clusterer::TopologyResponse, Error>;
+        #[method(name="clusterer_list_cap",param_kind=map)]
+        fn clusterer_list_cap(&self) -> Result<clusterer::CapabilitiesResponse, Error>;
+        #[method(name="clusterer_remove_node",param_kind=map)]
+        fn clusterer_remove_node(&self, cluster_id: usize, node_id: usize) -> Result<String, Error>;
+        #[method(name="clusterer_send_msg",param_kind=map)]
+        fn clusterer_send_msg(&self, cluster_id: usize, destination: usize, msg: String, tag: String) -> Result<String, Error>;
+        #[method(name="clusterer_broadcast_msg",param_kind=map)]
+        fn clusterer_broadcast_msg(&self, cluster_id: usize, msg: String, tag: String) -> Result<String, Error>;
+
+        // usrloc methods
+        #[method(name="ul_dump",param_kind=map)]
+        fn ul_dump(&self) -> Result<usrloc::DumpResponse, Error>;
+        #[method(name="ul_rm",param_kind=map)]
+        fn ul_rm(&self, table_name: String, aor: String) -> Result<String, Error>;
+        #[method(name="ul_rm_contact",param_kind=map)]
+        fn ul_rm_contact(&self, table_name: String, aor: String, contact: String) -> Result<String, Error>;
+        #[method(name="ul_flush")]
+        fn ul_flush(&self) -> Result<String, Error>;
+        #[method(name="ul_cluster_sync")]
+        fn ul_cluster_sync(&self) -> Result<String, Error>;
+
+        // tm methods
+        #[method(name="t_uac_dlg",param_kind=map)]
+        fn t_uac_dlg(&self, method: String, ruri: String, headers: String, next_hop: String, socket: String) -> Result<TUacDlgResponse, Error>;
+        #[method(name="t_uac_dlg",param_kind=map)]
+        fn t_uac_dlg_with_body(&self, method: String, ruri: String, headers: String, next_hop: String, socket: String, body: String) -> Result<TUacDlgResponse, Error>;
+        #[method(name="t_uac_cancel",param_kind=map)]
+        fn t_uac_cancel(&self, callid: String, cseq: String) -> Result<String, Error>;
+        #[method(name="t_hash")]
+        fn t_hash(&self) -> Result<Vec<tm::HashBucket>, Error>;
+        #[method(name="t_reply",param_kind=map)]
+        fn t_reply(&self, code: u16, reason: String, trans_id: tm::TransactionId, to_tag: String, new_headers: String) -> Result<String, Error>;
+        #[method(name="t_reply",param_kind=map)]
+        fn t_reply_with_body(&self, code: u16, reason: String, trans_id: tm::TransactionId, to_tag: String, new_headers: String, body: String) -> Result<String, Error>;
+
+        // uac_registrant methods
+        #[method(name="reg_list",param_kind=map)]
+        fn reg_list(&self) -> Result<RegListResponse, Error>;
+        #[method(name="reg_list",param_kind=map)]
+        fn reg_list_record(&self, aor: String, contact: String, registrar: String) -> Result<RegListRecordResponse, Error>;
+        #[method(name="reg_reload",param_kind=map)]
+        fn reg_reload(&self) -> Result<String, Error>;
+        #[method(name="reg_reload",param_kind=map)]
+        fn reg_reload_record(&self, aor: String, contact: String, registrar: String) -> Result<String, Error>;
+        #[method(name="reg_enable",param_kind=map)]
+        fn reg_enable(&self, aor: String, contact: String, registrar: String) -> Result<String, Error>;
+        #[method(name="reg_disable",param_kind=map)]
+        fn reg_disable(&self, aor: String, contact: String, registrar: String) -> Result<String, Error>;
+
+        // dialog module
+        #[method(name="dlg_list",param_kind=map)]
+        fn dlg_list(&self) -> Result<dialog::ListResponse, Error>;
+        #[method(name="dlg_list",param_kind=map)]
+        fn dlg_list_record(&self, callid: String, from_tag: String) -> Result<dialog::ListRecordResponse, Error>;
+        #[method(name="profile_get_size",param_kind=map)]
+        fn profile_get_size(&self, profile: String) -> Result<dialog::ProfileSizeResponse, Error>;
+        #[method(name="profile_get_size",param_kind=map)]
+        fn profile_get_size_value(&self, profile: String, value: String) -> Result<dialog::ProfileSizeResponse, Error>;
 
+        // b2b_entities module
+        #[method(name="b2be_list")]
+        fn b2be_list(&self) -> Result<b2b_entities::ListResponse, Error>;
+        // OpenSIPS doesn't appear to accept `null` for optional parameters, which is what Option would send, so leaving
+        // most of the optional types in every method for now.
+        #[method(name="ua_session_client_start",param_kind=map)]
+        fn ua_session_client_start(&self, ruri: String, to: String, from: String, proxy: String, body: String, extra_headers: Vec<String>, content_type: String, flags: String) -> Result<String, Error>;
+        #[method(name="ua_session_reply",param_kind=map)]
+        fn ua_session_reply(&self, key: String, method: String, code: usize, reason: String, body: String, extra_headers: Vec<String>, content_type: String) -> Result<String, Error>;
+        #[method(name="ua_session_update",param_kind=map)]
+        fn ua_session_update(&self, key: String, method: String, body: String, extra_headers: Vec<String>, content_type: String) -> Result<String, Error>;
+        #[method(name="ua_session_terminate",param_kind=map)]
+        fn ua_session_terminate(&self, key: String, extra_headers: Vec<String>) -> Result<String, Error>;
+        #[method(name="ua_session_list",param_kind=map)]
+        fn ua_session_list(&self) -> Result<Vec<b2b_entities::UASession>, Error>;
+        #[method(name="ua_session_list",param_kind=map)]
+        fn ua_session_list_with_key(&self, key: String) -> Result<b2b_entities::UASession, Error>;
+
+        // b2b_logic module
+        #[method(name="b2b_list")]
+        fn b2b_list(&self) -> Result<b2b_logic::ListResponse, Error>;
+        #[method(name="b2b_trigger_scenario",param_kind=map)]
+        fn b2b_trigger_scenario(&self, scenario_id: b2b_logic::ScenarioId, entity1: String, entity2: String, context: Vec<String>) -> Result<String, Error>;
+        #[method(name="b2b_bridge",param_kind=map)]
+        fn b2b_bridge(&self, dialog_id: b2b_logic::TupleKey, new_uri: String) -> Result<String, Error>;
+        #[method(name="b2b_bridge",param_kind=map)]
+        fn b2b_bridge_side(&self, dialog_id: b2b_logic::TupleKey, new_uri: String, flag: b2b_logic::BridgeSide) -> Result<String, Error>;
+        #[method(name="b2b_terminate_call",param_kind=map)]
+        fn b2b_terminate_call(&self, key: b2b_logic::TupleKey) -> Result<String, Error>;
+        #[method(name="b2b_bridge_2calls",param_kind=map)]
+        fn b2b_bridge_2calls(&self, dialog1_id: b2b_logic::TupleKey, dialog2_id: b2b_logic::TupleKey) -> Result<String, Error>;
+    }
 }
+pub use api::*;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct VersionResponse {
@@ -182,10 +256,32 @@ pub struct EventsListResponse {
 #[serde(rename_all="PascalCase")]
 pub struct TUacDlgResponse {
     pub status: String,
+    /// headers and body of the final reply; empty for requests that get none, such as ACK
     #[serde(default)]
     pub message: String,
 }
 
+impl TUacDlgResponse {
+    /// `status` parsed, e.g. to check for a 2xx.
+    pub fn status(&self) -> Result<tm::Status, String> {
+        self.status.parse()
+    }
+
+    /// The final reply, e.g. to look at the Retry-After of a 503. mi_tm leaves out the status
+    /// line, so it is taken from `status` unless the message has one.
+    pub fn reply(&self) -> Option<sip::SipMessage> {
+        if self.message.trim().is_empty() {
+            return None;
+        }
+        if let Ok(message) = self.message.parse() {
+            return Some(message);
+        }
+        let status = self.status().ok()?;
+        let start_line = sip::StartLine::Response { version: "SIP/2.0".to_string(), code: status.code, reason: status.reason };
+        Some(sip::SipMessage::from_parts(start_line, &self.message))
+    }
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all="snake_case")]
 pub enum RegEnabled {
@@ -289,13 +385,24 @@ pub mod dispatcher {
         pub destinations: Vec<Destination>,
     }
 
-    #[derive(Debug, Deserialize, Serialize)]
+    #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
     pub enum DestinationState {
         Active,
         Probing,
         Inactive,
     }
 
+    // ds_set_state takes the single letter form of the state
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    pub enum SetState {
+        #[serde(rename = "a")]
+        Active,
+        #[serde(rename = "i")]
+        Inactive,
+        #[serde(rename = "p")]
+        Probing,
+    }
+
     #[derive(Debug, Deserialize, Serialize)]
     pub struct Destination {
         #[serde(rename = "URI")]
@@ -319,6 +426,162 @@ pub mod dispatcher {
     }
 }
 
+pub mod tm {
+    use std::fmt;
+    use std::str::FromStr;
+    use super::*;
+
+    /// `hash:label` of a transaction, as `t_reply` takes it.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct TransactionId {
+        pub hash: u32,
+        pub label: u32,
+    }
+
+    impl FromStr for TransactionId {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, String> {
+            let (hash, label) = s.split_once(':').ok_or_else(|| format!("expected hash:label, got {:?}", s))?;
+            Ok(TransactionId {
+                hash: hash.trim().parse().map_err(|_| format!("bad hash {:?}", hash))?,
+                label: label.trim().parse().map_err(|_| format!("bad label {:?}", label))?,
+            })
+        }
+    }
+
+    impl TryFrom<String> for TransactionId {
+        type Error = String;
+
+        fn try_from(s: String) -> Result<Self, String> {
+            s.parse()
+        }
+    }
+
+    impl From<TransactionId> for String {
+        fn from(id: TransactionId) -> String {
+            id.to_string()
+        }
+    }
+
+    impl fmt::Display for TransactionId {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}:{}", self.hash, self.label)
+        }
+    }
+
+    /// One entry of `t_hash`; buckets that never held a transaction are left out.
+    #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct HashBucket {
+        pub index: usize,
+        /// transactions in the bucket now
+        pub current: u64,
+        /// transactions ever added to it
+        pub total: u64,
+    }
+
+    /// A reply status such as `200 OK`.
+    #[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct Status {
+        pub code: u16,
+        pub reason: String,
+    }
+
+    impl Status {
+        pub fn is_provisional(&self) -> bool {
+            (100..200).contains(&self.code)
+        }
+
+        pub fn is_success(&self) -> bool {
+            (200..300).contains(&self.code)
+        }
+    }
+
+    impl FromStr for Status {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, String> {
+            let s = s.trim();
+            let (code, reason) = s.split_once(' ').unwrap_or((s, ""));
+            Ok(Status {
+                code: code.parse().map_err(|_| format!("bad status code in {:?}", s))?,
+                reason: reason.trim().to_string(),
+            })
+        }
+    }
+
+    impl TryFrom<String> for Status {
+        type Error = String;
+
+        fn try_from(s: String) -> Result<Self, String> {
+            s.parse()
+        }
+    }
+
+    impl From<Status> for String {
+        fn from(status: Status) -> String {
+            status.to_string()
+        }
+    }
+
+    impl fmt::Display for Status {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.code)?;
+            if !self.reason.is_empty() {
+                write!(f, " {}", self.reason)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_t_uac_dlg_response() {
+            let response: TUacDlgResponse = serde_json::from_str(r#"{"Status": "486 Busy Here",
+                "Message": "SIP/2.0 486 Busy Here\r\nTo: <sip:bob@example.com>;tag=b1\r\nContent-Length: 0\r\n\r\n"}"#).unwrap();
+            assert_eq!(response.status(), Ok(Status { code: 486, reason: "Busy Here".into() }));
+            assert!(!response.status().unwrap().is_success());
+            let reply = response.reply().unwrap();
+            assert_eq!(reply.status_code(), Some(486));
+            assert_eq!(reply.headers.get("to"), Some("<sip:bob@example.com>;tag=b1"));
+
+            // as mi_tm prints it: headers and body without the status line
+            let response: TUacDlgResponse = serde_json::from_str(r#"{"Status": "200 OK",
+                "Message": "Via: SIP/2.0/UDP 10.0.0.1:5060;branch=z9hG4bK1f2.7c3\r\nFrom: <sip:alice@example.com>;tag=a1\r\nTo: <sip:bob@example.com>;tag=b2\r\nCall-ID: 1f2-7c3@10.0.0.1\r\nCSeq: 1 INVITE\r\nContact: <sip:bob@10.0.0.9:5060>\r\nContent-Type: application/sdp\r\nContent-Length: 4\r\n\r\nv=0\n"}"#).unwrap();
+            let reply = response.reply().unwrap();
+            assert_eq!(reply.status_code(), Some(200));
+            assert_eq!(reply.method(), Some("INVITE"));
+            assert_eq!(reply.headers.get("Via"), Some("SIP/2.0/UDP 10.0.0.1:5060;branch=z9hG4bK1f2.7c3"));
+            assert_eq!(reply.headers.contact()[0].uri, "sip:bob@10.0.0.9:5060");
+            assert_eq!(reply.body, "v=0\n");
+
+            // an unparseable message doesn't cost the status
+            let response: TUacDlgResponse = serde_json::from_str(r#"{"Status": "408 Request Timeout", "Message": "???"}"#).unwrap();
+            assert_eq!(response.status().unwrap().code, 408);
+            assert_eq!(response.reply().unwrap().status_code(), Some(408));
+            let response: TUacDlgResponse = serde_json::from_str(r#"{"Status": "200 OK", "Message": ""}"#).unwrap();
+            assert!(response.reply().is_none());
+
+            // nor does a status that doesn't parse cost the response
+            let response: TUacDlgResponse = serde_json::from_str(r#"{"Status": "Timeout", "Message": "???"}"#).unwrap();
+            assert_eq!(response.status, "Timeout");
+            assert!(response.status().is_err() && response.reply().is_none());
+            assert_eq!(Status { code: 200, reason: String::new() }.to_string(), "200");
+            assert_eq!("183 Session Progress".parse::<Status>().unwrap().to_string(), "183 Session Progress");
+
+            let id: TransactionId = serde_json::from_str(r#""3071:1402598934""#).unwrap();
+            assert_eq!(id, TransactionId { hash: 3071, label: 1402598934 });
+            assert_eq!(serde_json::to_string(&id).unwrap(), r#""3071:1402598934""#);
+            assert!("3071".parse::<TransactionId>().is_err());
+        }
+    }
+}
+
 pub mod clusterer {
     use super::*;
 
@@ -378,6 +641,103 @@ pub mod clusterer {
         pub cluster: usize,
         pub state: TagState,
     }
+
+    #[derive(Copy, Clone, Serialize_repr, Deserialize_repr, PartialEq, Eq, Debug)]
+    #[repr(u8)]
+    pub enum Status {
+        Disabled = 0,
+        Enabled = 1,
+    }
+
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct TopologyResponse {
+        #[serde(rename = "Clusters")]
+        pub clusters: Vec<ClusterTopology>,
+    }
+
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ClusterTopology {
+        pub cluster_id: usize,
+        #[serde(rename = "Nodes")]
+        pub nodes: Vec<TopologyNode>,
+    }
+
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct TopologyNode {
+        pub node_id: i64,
+        #[serde(default, rename = "Neighbours")]
+        pub neighbours: Vec<i64>,
+    }
+
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct CapabilitiesResponse {
+        #[serde(rename = "Clusters")]
+        pub clusters: Vec<ClusterCapabilities>,
+    }
+
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ClusterCapabilities {
+        pub cluster_id: usize,
+        #[serde(default, rename = "Capabilities")]
+        pub capabilities: Vec<Capability>,
+    }
+
+    #[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+    pub enum CapabilityState {
+        #[default]
+        #[serde(rename = "Ok")]
+        Ok,
+        #[serde(rename = "not synced")]
+        NotSynced,
+        #[serde(rename = "sync pending")]
+        SyncPending,
+        #[serde(rename = "syncing")]
+        Syncing,
+        #[serde(other)]
+        Unknown,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum CapabilityEnabled {
+        #[default]
+        Yes,
+        No,
+    }
+
+    #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct Capability {
+        pub name: String,
+        pub state: CapabilityState,
+        #[serde(default)]
+        pub enabled: CapabilityEnabled,
+    }
+
+    /// Sends JSON encoded messages over the cluster bus with `clusterer_send_msg` and
+    /// `clusterer_broadcast_msg`; the other nodes see them as E_CLUSTERER_REQ_RECEIVED.
+    pub struct ClusterBus<'a, C> {
+        pub client: &'a C,
+        pub cluster_id: usize,
+        pub tag: String,
+    }
+
+    impl<'a, C> ClusterBus<'a, C>
+        where C: OpenSIPSClient + Sync
+    {
+        pub fn new(client: &'a C, cluster_id: usize, tag: impl Into<String>) -> Self {
+            ClusterBus { client, cluster_id, tag: tag.into() }
+        }
+
+        pub async fn send<T: serde::Serialize>(&self, destination: usize, msg: &T) -> Result<String, jsonrpsee::core::Error> {
+            let msg = serde_json::to_string(msg)?;
+            self.client.clusterer_send_msg(self.cluster_id, destination, msg, self.tag.clone()).await
+        }
+
+        pub async fn broadcast<T: serde::Serialize>(&self, msg: &T) -> Result<String, jsonrpsee::core::Error> {
+            let msg = serde_json::to_string(msg)?;
+            self.client.clusterer_broadcast_msg(self.cluster_id, msg, self.tag.clone()).await
+        }
+    }
 }
 
 pub mod usrloc {
@@ -474,6 +834,20 @@ pub mod dialog {
     pub struct ListRecordResponse {
         pub dialog: Dialog,
     }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct ProfileSize {
+        pub name: String,
+        #[serde(default)]
+        pub value: Option<String>,
+        pub count: usize,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct ProfileSizeResponse {
+        pub profile: ProfileSize,
+    }
 }
 
 pub mod b2b_entities {
@@ -512,14 +886,20 @@ pub mod b2b_entities {
         Terminated = 7, /* Terminated dialog */
     }
 
+    /// A b2b_entities dialog, as `ua_session_list` and `b2be_list` print it.
     #[derive(Clone, Debug, Serialize, Deserialize)]
     pub struct UASession {
         pub dlg: i64,
+        // b2be_list calls it param
+        #[serde(default, alias = "param")]
         pub logic_key: String,
+        #[serde(default)]
         pub mod_name: String,
         pub state: B2BState,
         pub last_invite_cseq: usize,
+        #[serde(default)]
         pub last_method: usize,
+        #[serde(default)]
         pub last_reply_code: usize,
         pub db_flag: DBFlag,
         pub ruri: String,
@@ -533,6 +913,127 @@ pub mod b2b_entities {
         pub cseq: Cseq,
         pub contact: Contact,
         pub send_sock: String,
+        #[serde(default)]
         pub tm_tran: String,
     }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct Leg {
+        pub tag: String,
+        #[serde(default)]
+        pub cseq: i64,
+        #[serde(default)]
+        pub contact: String,
+        #[serde(default)]
+        pub route: Option<String>,
+    }
+
+    /// One entity from `b2be_list`.
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct Entity {
+        #[serde(flatten)]
+        pub session: UASession,
+        #[serde(default)]
+        pub legs: Vec<Leg>,
+    }
+
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    pub struct ListResponse {
+        #[serde(default, rename = "Server Entities")]
+        pub servers: Vec<Entity>,
+        #[serde(default, rename = "Client Entities")]
+        pub clients: Vec<Entity>,
+    }
+}
+
+pub mod b2b_logic {
+    use super::*;
+
+    /// Key of a b2b_logic tuple, the `dialog_id`/`key` of the b2b_logic MI commands.
+    #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct TupleKey(pub String);
+
+    /// A scenario as passed to `b2b_trigger_scenario`, e.g. `refer` or a script defined one.
+    #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    #[serde(transparent)]
+    pub struct ScenarioId(pub String);
+
+    impl ScenarioId {
+        pub fn refer() -> Self {
+            ScenarioId("refer".to_string())
+        }
+    }
+
+    /// Which side of the call `b2b_bridge` replaces.
+    #[derive(Copy, Clone, Serialize_repr, Deserialize_repr, PartialEq, Eq, Debug)]
+    #[repr(u8)]
+    pub enum BridgeSide {
+        Caller = 0,
+        Callee = 1,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct TupleEntity {
+        pub index: usize,
+        pub key: String,
+        #[serde(rename = "type")]
+        pub entity_type: usize,
+        #[serde(default)]
+        pub scenario_id: Option<ScenarioId>,
+        #[serde(default)]
+        pub state: Option<usize>,
+        #[serde(default)]
+        pub peer: Option<String>,
+        #[serde(default)]
+        pub to_uri: Option<String>,
+        #[serde(default)]
+        pub from_uri: Option<String>,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct Tuple {
+        pub key: TupleKey,
+        #[serde(default)]
+        pub scenario_id: Option<ScenarioId>,
+        #[serde(default)]
+        pub state: Option<i64>,
+        #[serde(default)]
+        pub lifetime: u64,
+        #[serde(default)]
+        pub entities: Vec<TupleEntity>,
+        #[serde(default)]
+        pub bridge_entities: Vec<TupleEntity>,
+    }
+
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct ListResponse {
+        #[serde(default)]
+        pub tuples: Vec<Tuple>,
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_list_decode() {
+            let entities: b2b_entities::ListResponse = serde_json::from_str(r#"{"Server Entities": [{
+                "dlg": 3, "param": "B2B.436.1", "mod_name": "b2b_logic", "state": 5, "last_invite_cseq": 1,
+                "last_method": 0, "last_reply_code": 200, "db_flag": 0, "ruri": "sip:bob@example.com", "callid": "c1",
+                "from": "", "from_uri": "sip:alice@example.com", "from_tag": "f1", "to": "", "to_uri": "sip:bob@example.com",
+                "to_tag": "t1", "cseq": {"caller": 1, "callee": 0}, "contact": {"caller": "sip:alice@10.0.0.1", "callee": ""},
+                "send_sock": "udp:10.0.0.9:5060", "legs": [{"tag": "t1", "cseq": 1, "contact": "sip:bob@10.0.0.2"}]
+            }]}"#).unwrap();
+            assert_eq!(entities.servers[0].session.logic_key, "B2B.436.1");
+            assert_eq!(entities.servers[0].legs[0].tag, "t1");
+            assert!(entities.clients.is_empty());
+
+            let tuples: ListResponse = serde_json::from_str(r#"{"Tuples": [{"key": "B2B.436.1", "scenario_id": "refer",
+                "lifetime": 0, "entities": [{"index": 0, "key": "B2B.436.1", "type": 0, "peer": "B2B.436.2"}]}]}"#).unwrap();
+            assert_eq!(tuples.tuples[0].key, TupleKey("B2B.436.1".into()));
+            assert_eq!(tuples.tuples[0].scenario_id, Some(ScenarioId::refer()));
+        }
+    }
 }
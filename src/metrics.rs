@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Counters shared between a receiver and whoever watches it. Hand the same `Arc` to the
+/// receiver and read it with [`ReceiverMetrics::snapshot`].
+#[derive(Debug, Default)]
+pub struct ReceiverMetrics {
+    datagrams: AtomicU64,
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+    queue_depth: AtomicUsize,
+    max_queue_depth: AtomicUsize,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    notifications: BTreeMap<String, u64>,
+    decode_errors: BTreeMap<String, u64>,
+    handler_calls: u64,
+    handler_total: Duration,
+    handler_max: Duration,
+}
+
+/// Point in time copy of [`ReceiverMetrics`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReceiverStats {
+    pub datagrams_received: u64,
+    /// datagrams ignored because of the source allow-list
+    pub rejected_sources: u64,
+    /// notifications thrown away by a full stream buffer or dispatch queue
+    pub dropped: u64,
+    /// decoded notifications per event name
+    pub notifications: BTreeMap<String, u64>,
+    /// undecodable payloads per event name, `<invalid>` when the payload isn't JSON at all
+    pub decode_errors: BTreeMap<String, u64>,
+    pub handler_calls: u64,
+    pub handler_latency_total: Duration,
+    pub handler_latency_max: Duration,
+    pub queue_depth: usize,
+    pub max_queue_depth: usize,
+}
+
+impl ReceiverStats {
+    pub fn handler_latency_mean(&self) -> Option<Duration> {
+        (self.handler_calls > 0).then(|| {
+            Duration::from_nanos((self.handler_latency_total.as_nanos() / self.handler_calls as u128) as u64)
+        })
+    }
+}
+
+impl ReceiverMetrics {
+    pub fn snapshot(&self) -> ReceiverStats {
+        let counts = self.counts.lock().unwrap();
+        ReceiverStats {
+            datagrams_received: self.datagrams.load(Ordering::Relaxed),
+            rejected_sources: self.rejected.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            notifications: counts.notifications.clone(),
+            decode_errors: counts.decode_errors.clone(),
+            handler_calls: counts.handler_calls,
+            handler_latency_total: counts.handler_total,
+            handler_latency_max: counts.handler_max,
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            max_queue_depth: self.max_queue_depth.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_datagram(&self) {
+        self.datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_notification(&self, event: &str) {
+        *self.counts.lock().unwrap().notifications.entry(event.to_string()).or_default() += 1;
+    }
+
+    /// Counts the failure against the `method` of the payload, if it has one.
+    pub fn record_decode_error(&self, payload: &[u8]) {
+        let event = match serde_json::from_slice::<serde_json::Value>(payload) {
+            Ok(v) => v.get("method")
+                .and_then(|m| m.as_str())
+                .unwrap_or("<unknown>")
+                .to_string(),
+            Err(_) => "<invalid>".to_string(),
+        };
+        *self.counts.lock().unwrap().decode_errors.entry(event).or_default() += 1;
+    }
+
+    pub fn record_handler(&self, latency: Duration) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.handler_calls += 1;
+        counts.handler_total += latency;
+        counts.handler_max = counts.handler_max.max(latency);
+    }
+
+    pub fn record_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+        self.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let metrics = ReceiverMetrics::default();
+        metrics.record_datagram();
+        metrics.record_rejected();
+        metrics.record_notification("E_DISPATCHER_STATUS");
+        metrics.record_notification("E_DISPATCHER_STATUS");
+        metrics.record_decode_error(br#"{"method":"E_UNKNOWN","params":{}}"#);
+        metrics.record_decode_error(br#"{"params":{}}"#);
+        metrics.record_decode_error(b"{not json");
+        metrics.record_handler(Duration::from_millis(10));
+        metrics.record_handler(Duration::from_millis(30));
+        metrics.record_queue_depth(5);
+        metrics.record_queue_depth(2);
+
+        let stats = metrics.snapshot();
+        assert_eq!((stats.datagrams_received, stats.rejected_sources), (1, 1));
+        assert_eq!(stats.notifications["E_DISPATCHER_STATUS"], 2);
+        assert_eq!(stats.decode_errors.iter().map(|(k, v)| (k.as_str(), *v)).collect::<Vec<_>>(),
+            vec![("<invalid>", 1), ("<unknown>", 1), ("E_UNKNOWN", 1)]);
+        assert_eq!((stats.handler_calls, stats.handler_latency_max), (2, Duration::from_millis(30)));
+        assert_eq!(stats.handler_latency_mean(), Some(Duration::from_millis(20)));
+        assert_eq!((stats.queue_depth, stats.max_queue_depth), (2, 5));
+
+        // more calls than fit in a u32
+        let stats = ReceiverStats { handler_calls: 1 << 32, handler_latency_total: Duration::from_secs(1 << 32), ..Default::default() };
+        assert_eq!(stats.handler_latency_mean(), Some(Duration::from_secs(1)));
+        assert_eq!(ReceiverStats::default().handler_latency_mean(), None);
+    }
+}
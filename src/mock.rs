@@ -0,0 +1,266 @@
+//! An in-process stand-in for OpenSIPS' `mi_http` and event delivery, for testing code that
+//! uses the `OpenSIPS` trait without a running OpenSIPS.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use jsonrpsee::http_client::{HttpClient, HttpClientBuilder};
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::time::sleep;
+use super::*;
+
+/// JSON-RPC error returned by a mocked method.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl MockError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        MockError { code, message: message.into() }
+    }
+}
+
+type Handler = Arc<dyn Fn(&Value) -> Result<Value, MockError> + Send + Sync>;
+
+#[derive(Default)]
+struct MockState {
+    handlers: HashMap<String, Handler>,
+    delays: HashMap<String, Duration>,
+    calls: Vec<(String, Value)>,
+    // event name -> sockets, as given to event_subscribe
+    subscriptions: HashMap<String, Vec<String>>,
+}
+
+pub struct MockOpenSIPS {
+    addr: SocketAddr,
+    state: Arc<Mutex<MockState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockOpenSIPS {
+    /// Starts serving on a random localhost port. `event_subscribe` is handled out of the box.
+    pub async fn start() -> Result<Self, hyper::Error> {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req)))
+            }
+        });
+        let server = Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?.serve(make_service);
+        let addr = server.local_addr();
+        let (shutdown, rx) = oneshot::channel();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = rx.await;
+        }));
+
+        let mock = MockOpenSIPS { addr, state, shutdown: Some(shutdown) };
+        let subscriptions = mock.state.clone();
+        mock.respond_with("event_subscribe", move |params| {
+            let (Some(event), Some(socket)) = (params["event"].as_str(), params["socket"].as_str()) else {
+                return Err(MockError::new(-32602, "Invalid params"));
+            };
+            subscriptions.lock().unwrap()
+                .subscriptions
+                .entry(event.to_string())
+                .or_default()
+                .push(socket.to_string());
+            Ok(json!("OK"))
+        });
+        Ok(mock)
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/mi", self.addr)
+    }
+
+    pub fn client(&self) -> HttpClient {
+        HttpClientBuilder::default()
+            .build(self.url())
+            .expect("mock url is valid")
+    }
+
+    /// Always answer `method` with `result`.
+    pub fn respond(&self, method: &str, result: impl serde::Serialize) {
+        let result = serde_json::to_value(result).expect("mock result serializes");
+        self.respond_with(method, move |_| Ok(result.clone()));
+    }
+
+    /// Answer `method` by calling `f` with the request params.
+    pub fn respond_with<F>(&self, method: &str, f: F)
+        where F: Fn(&Value) -> Result<Value, MockError> + Send + Sync + 'static
+    {
+        self.state.lock().unwrap().handlers.insert(method.to_string(), Arc::new(f));
+    }
+
+    pub fn fail(&self, method: &str, error: MockError) {
+        self.respond_with(method, move |_| Err(error.clone()));
+    }
+
+    /// Hold every answer to `method` back by `delay`.
+    pub fn delay(&self, method: &str, delay: Duration) {
+        self.state.lock().unwrap().delays.insert(method.to_string(), delay);
+    }
+
+    /// Params of every call to `method` so far, oldest first.
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        self.state.lock().unwrap()
+            .calls
+            .iter()
+            .filter(|(m, _)| m == method)
+            .map(|(_, params)| params.clone())
+            .collect()
+    }
+
+    pub fn assert_called_with(&self, method: &str, params: Value) {
+        let calls = self.calls(method);
+        assert!(calls.contains(&params), "{} not called with {}, calls: {:?}", method, params, calls);
+    }
+
+    /// Sends `notification` to every socket subscribed to its event, like OpenSIPS'
+    /// event_datagram and event_stream modules. Returns how many sockets it went to.
+    pub async fn notify(&self, notification: &Notification) -> io::Result<usize> {
+        let mut payload = serde_json::to_value(notification)?;
+        payload["jsonrpc"] = json!("2.0");
+        let event = payload["method"].as_str().unwrap_or_default().to_string();
+        let sockets = self.state.lock().unwrap()
+            .subscriptions
+            .get(&event)
+            .cloned()
+            .unwrap_or_default();
+        let payload = serde_json::to_vec(&payload)?;
+        for socket in &sockets {
+            send_event(socket, &payload).await?;
+        }
+        Ok(sockets.len())
+    }
+
+    /// Sends each notification after waiting the paired delay.
+    pub async fn play(&self, script: Vec<(Duration, Notification)>) -> io::Result<()> {
+        for (delay, notification) in script {
+            sleep(delay).await;
+            self.notify(&notification).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MockOpenSIPS {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+async fn send_event(socket: &str, payload: &[u8]) -> io::Result<()> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("bad event socket {}", socket));
+    let (proto, addr) = socket.split_once(':').ok_or_else(invalid)?;
+    let addr: SocketAddr = addr.parse().map_err(|_| invalid())?;
+    match proto {
+        "udp" => {
+            let udp = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+            udp.send_to(payload, addr).await?;
+        }
+        "tcp" => {
+            let mut tcp = TcpStream::connect(addr).await?;
+            tcp.write_all(payload).await?;
+        }
+        _ => return Err(invalid()),
+    }
+    Ok(())
+}
+
+async fn handle(state: Arc<Mutex<MockState>>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let reply = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Array(batch)) => {
+            let mut replies = Vec::new();
+            for request in batch {
+                replies.extend(call(&state, request).await);
+            }
+            Value::Array(replies)
+        }
+        Ok(request) => call(&state, request).await.unwrap_or(Value::Null),
+        Err(_) => error_reply(Value::Null, MockError::new(-32700, "Parse error")),
+    };
+    let body = if reply.is_null() { Vec::new() } else { serde_json::to_vec(&reply).unwrap_or_default() };
+    Ok(Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))
+        .expect("static response parts are valid"))
+}
+
+// Returns None for notifications.
+async fn call(state: &Mutex<MockState>, request: Value) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let method = request["method"].as_str().unwrap_or_default().to_string();
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    let (handler, delay) = {
+        let mut state = state.lock().unwrap();
+        state.calls.push((method.clone(), params.clone()));
+        (state.handlers.get(&method).cloned(), state.delays.get(&method).copied())
+    };
+    if let Some(delay) = delay {
+        sleep(delay).await;
+    }
+    let result = match handler {
+        Some(handler) => handler(&params),
+        None => Err(MockError::new(-32601, "Method not found")),
+    };
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => error_reply(id, e),
+    })
+}
+
+fn error_reply(id: Value, error: MockError) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": error.code, "message": error.message}})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_calls_and_events() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        mock.respond("version", json!({"Server": "OpenSIPS (3.4.0 (x86_64/linux))"}));
+        mock.fail("ds_reload", MockError::new(500, "Server Internal Error"));
+        let client = mock.client();
+
+        assert_eq!(client.version().await.unwrap().server, "OpenSIPS (3.4.0 (x86_64/linux))");
+        assert!(client.ds_reload().await.is_err());
+        assert!(client.ul_flush().await.is_err());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = format!("udp:{}", socket.local_addr().unwrap());
+        client.event_subscribe("E_DISPATCHER_STATUS".to_string(), target.clone()).await.unwrap();
+        mock.assert_called_with("event_subscribe", json!({"event": "E_DISPATCHER_STATUS", "socket": target}));
+
+        let status = DispatcherStatus {
+            partition: "default".into(),
+            group: "1".into(),
+            address: "sip:10.0.0.1".into(),
+            status: DispatcherState::Inactive,
+        };
+        assert_eq!(mock.notify(&Notification::EDispatcherStatus(status)).await.unwrap(), 1);
+        let mut buf = vec![0u8; 65536];
+        let size = socket.recv(&mut buf).await.unwrap();
+        match serde_json::from_slice(&buf[..size]).unwrap() {
+            Notification::EDispatcherStatus(s) => assert_eq!(s.status, DispatcherState::Inactive),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Recording of MI exchanges and event notifications to a JSON-lines file, and replay of
+//! those recordings through the same client and receiver interfaces.
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use jsonrpsee::core::{async_trait, Error, JsonRawValue};
+use jsonrpsee::core::client::{BatchResponse, ClientT};
+use jsonrpsee::core::params::BatchRequestBuilder;
+use jsonrpsee::core::traits::ToRpcParams;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::time::{sleep, Instant};
+use tracing::debug;
+use super::*;
+use super::transport::{MiClient, MiTransport};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RecordedError {
+    pub code: i32,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+/// One line of a recording; `time` is milliseconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Call {
+        time: u64,
+        elapsed_ms: u64,
+        method: String,
+        params: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<RecordedError>,
+    },
+    Notification {
+        time: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<SocketAddr>,
+        payload: Value,
+    },
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Appends records to a JSON-lines file. Clones share the same file.
+///
+/// Hand it to a receiver with `UdpNotificationReceiver::recorder` to record every datagram it
+/// gets, and wrap clients with [`Recorder::client`] to record their calls.
+#[derive(Clone)]
+pub struct Recorder {
+    out: Arc<tokio::sync::Mutex<BufWriter<tokio::fs::File>>>,
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Recorder").finish()
+    }
+}
+
+impl Recorder {
+    pub async fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(path).await?;
+        Ok(Recorder { out: Arc::new(tokio::sync::Mutex::new(BufWriter::new(file))) })
+    }
+
+    pub async fn record(&self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut out = self.out.lock().await;
+        out.write_all(&line).await?;
+        out.flush().await
+    }
+
+    /// Wraps a client so every call through it is recorded.
+    pub fn client<C>(&self, inner: C) -> RecordingClient<C> {
+        RecordingClient { inner, recorder: self.clone() }
+    }
+
+    pub async fn record_notification(&self, source: Option<SocketAddr>, notification: &Notification) -> io::Result<()> {
+        let mut payload = serde_json::to_value(notification)?;
+        payload["jsonrpc"] = json!("2.0");
+        self.record(&Record::Notification { time: now_ms(), source, payload }).await
+    }
+
+    /// Records a datagram as received, before any decoding.
+    pub async fn record_payload(&self, source: Option<SocketAddr>, payload: &[u8]) -> io::Result<()> {
+        let payload = serde_json::from_slice(payload)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(payload).into_owned()));
+        self.record(&Record::Notification { time: now_ms(), source, payload }).await
+    }
+
+    async fn record_call(&self, started: Instant, method: &str, params: Value, result: &Result<Value, Error>) {
+        let (result, error) = match result {
+            Ok(value) => (Some(value.clone()), None),
+            Err(Error::Call(e)) => (None, Some(RecordedError {
+                code: e.code(),
+                message: e.message().to_string(),
+                data: e.data().and_then(|d| serde_json::from_str(d.get()).ok()),
+            })),
+            // transport failures aren't something a replay can reproduce
+            Err(_) => return,
+        };
+        let record = Record::Call {
+            time: now_ms(),
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            method: method.to_string(),
+            params,
+            result,
+            error,
+        };
+        if let Err(e) = self.record(&record).await {
+            debug!("failed to record {}: {:?}", method, e);
+        }
+    }
+}
+
+struct RawParams(Option<Box<JsonRawValue>>);
+
+impl ToRpcParams for RawParams {
+    fn to_rpc_params(self) -> Result<Option<Box<JsonRawValue>>, Error> {
+        Ok(self.0)
+    }
+}
+
+fn params_value(params: &Option<Box<JsonRawValue>>) -> Value {
+    params.as_ref()
+        .and_then(|p| serde_json::from_str(p.get()).ok())
+        .unwrap_or(Value::Null)
+}
+
+pub struct RecordingClient<C> {
+    inner: C,
+    recorder: Recorder,
+}
+
+impl<C> RecordingClient<C> {
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+#[async_trait]
+impl<C: ClientT + Send + Sync> ClientT for RecordingClient<C> {
+    async fn notification<Params>(&self, method: &str, params: Params) -> Result<(), Error>
+        where Params: ToRpcParams + Send
+    {
+        self.inner.notification(method, params).await
+    }
+
+    async fn request<R, Params>(&self, method: &str, params: Params) -> Result<R, Error>
+        where R: DeserializeOwned,
+              Params: ToRpcParams + Send
+    {
+        let params = params.to_rpc_params()?;
+        let recorded_params = params_value(&params);
+        let started = Instant::now();
+        let result = self.inner.request::<Value, _>(method, RawParams(params)).await;
+        self.recorder.record_call(started, method, recorded_params, &result).await;
+        Ok(serde_json::from_value(result?)?)
+    }
+
+    async fn batch_request<'a, R>(&self, batch: BatchRequestBuilder<'a>) -> Result<BatchResponse<'a, R>, Error>
+        where R: DeserializeOwned + fmt::Debug + 'a
+    {
+        let requests = batch.build()?;
+        let mut inner_batch = BatchRequestBuilder::new();
+        let mut recorded = Vec::with_capacity(requests.len());
+        for (method, params) in requests {
+            recorded.push((method, params_value(&params)));
+            inner_batch.insert(method, RawParams(params))?;
+        }
+        let started = Instant::now();
+        let responses = self.inner.batch_request::<Value>(inner_batch).await?;
+
+        let mut results = Vec::with_capacity(recorded.len());
+        for ((method, params), entry) in recorded.into_iter().zip(responses) {
+            let result = entry.map_err(|e| e.into_owned());
+            let for_record = result.clone().map_err(Error::Call);
+            self.recorder.record_call(started, method, params, &for_record).await;
+            results.push(result);
+        }
+
+        let mut entries = Vec::with_capacity(results.len());
+        let (mut successful, mut failed) = (0, 0);
+        for result in results {
+            entries.push(match result {
+                Ok(value) => {
+                    successful += 1;
+                    Ok(serde_json::from_value(value)?)
+                }
+                Err(e) => {
+                    failed += 1;
+                    Err(e)
+                }
+            });
+        }
+        Ok(BatchResponse::new(successful, entries, failed))
+    }
+}
+
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Answers requests from recorded calls. Each recorded call is used once, in recording order,
+/// matched on method and params.
+pub struct ReplayTransport {
+    calls: Mutex<Vec<Option<Record>>>,
+    realtime: bool,
+}
+
+pub type ReplayClient = MiClient<ReplayTransport>;
+
+impl ReplayClient {
+    /// With `realtime` set, each answer is delayed by the latency seen when it was recorded.
+    pub fn from_records(records: &[Record], realtime: bool) -> Self {
+        let calls = records.iter()
+            .filter(|r| matches!(r, Record::Call { .. }))
+            .cloned()
+            .map(Some)
+            .collect();
+        MiClient::new(ReplayTransport { calls: Mutex::new(calls), realtime })
+    }
+}
+
+impl ReplayTransport {
+    fn answer(&self, request: &Value) -> (Value, u64) {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let method = request["method"].as_str().unwrap_or_default();
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let mut calls = self.calls.lock().unwrap();
+        let found = calls.iter_mut()
+            .find(|c| matches!(c, Some(Record::Call { method: m, params: p, .. }) if m == method && *p == params))
+            .and_then(Option::take);
+        match found {
+            Some(Record::Call { result: Some(result), elapsed_ms, .. }) =>
+                (json!({"jsonrpc": "2.0", "id": id, "result": result}), elapsed_ms),
+            Some(Record::Call { error: Some(error), elapsed_ms, .. }) =>
+                (json!({"jsonrpc": "2.0", "id": id, "error": error}), elapsed_ms),
+            _ => (json!({"jsonrpc": "2.0", "id": id, "error": {
+                "code": -32601,
+                "message": format!("no recorded call to {} with params {}", method, params),
+            }}), 0),
+        }
+    }
+}
+
+#[async_trait]
+impl MiTransport for ReplayTransport {
+    async fn round_trip(&self, request: String, _ids: &[u64]) -> Result<Vec<u8>, Error> {
+        let (reply, elapsed_ms) = match serde_json::from_str(&request)? {
+            Value::Array(batch) => {
+                let answers: Vec<_> = batch.iter().map(|r| self.answer(r)).collect();
+                let elapsed = answers.iter().map(|(_, e)| *e).max().unwrap_or(0);
+                (Value::Array(answers.into_iter().map(|(a, _)| a).collect()), elapsed)
+            }
+            request => self.answer(&request),
+        };
+        if self.realtime {
+            sleep(Duration::from_millis(elapsed_ms)).await;
+        }
+        Ok(serde_json::to_vec(&reply)?)
+    }
+
+    async fn send(&self, _notification: String) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Decodes recorded notifications and hands them to `f`, like `UdpNotificationReceiver::run`.
+/// With `realtime` set, the gaps between notifications are kept. Payloads that no longer decode
+/// are returned as errors, which is how schema changes show up.
+pub async fn replay_notifications<F>(records: &[Record], realtime: bool, f: impl Fn(Notification) -> F) -> serde_json::Result<()>
+    where F: Future<Output = ()>
+{
+    let mut last_time = None;
+    for record in records {
+        let Record::Notification { time, payload, .. } = record else {
+            continue;
+        };
+        if let (true, Some(last)) = (realtime, last_time) {
+            sleep(Duration::from_millis(time.saturating_sub(last))).await;
+        }
+        last_time = Some(*time);
+        f(serde_json::from_value(payload.clone())?).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockOpenSIPS;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("opensips-recording-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mock = MockOpenSIPS::start().await.unwrap();
+        mock.respond("version", json!({"Server": "OpenSIPS (3.4.0 (x86_64/linux))"}));
+        let recorder = Recorder::create(&path).await.unwrap();
+        let client = recorder.client(mock.client());
+        assert_eq!(client.version().await.unwrap().server, "OpenSIPS (3.4.0 (x86_64/linux))");
+        assert!(client.ds_reload().await.is_err());
+        let change = ClustererNodeStateChange { cluster_id: 1, node_id: 2, new_state: ClusterNodeState::Down };
+        recorder.record_notification(None, &Notification::EClustererNodeStateChange(change)).await.unwrap();
+
+        // and what a receiver gets, as it gets it
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(UdpNotificationReceiver::new(socket).recorder(recorder.clone()).run(move |n| {
+            let _ = tx.send(n);
+            async {}
+        }));
+        let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.send_to(br#"{"jsonrpc":"2.0","method":"E_CLUSTERER_NODE_STATE_CHANGE","params":{"cluster_id":1,"node_id":3,"new_state":1}}"#, addr).await.unwrap();
+        rx.recv().await.unwrap();
+
+        let records = load(&path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert!(matches!(&records[3], Record::Notification { source: Some(source), .. } if *source == sender.local_addr().unwrap()));
+        let replay = ReplayClient::from_records(&records, false);
+        assert_eq!(replay.version().await.unwrap().server, "OpenSIPS (3.4.0 (x86_64/linux))");
+        assert!(replay.ds_reload().await.is_err());
+        assert!(replay.version().await.is_err());
+
+        let seen = Mutex::new(Vec::new());
+        replay_notifications(&records, false, |n| {
+            seen.lock().unwrap().push(n);
+            async {}
+        }).await.unwrap();
+        assert!(matches!(seen.lock().unwrap()[..], [
+            Notification::EClustererNodeStateChange(ClustererNodeStateChange { node_id: 2, .. }),
+            Notification::EClustererNodeStateChange(ClustererNodeStateChange { node_id: 3, .. }),
+        ]));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::interval;
+use tracing::{debug, warn};
+use super::*;
+use super::sip::{parse_params, NameAddr};
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// days from 1970-01-01 to a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parses the `ctime()` format uac_registrant prints dates in, e.g. `Tue Oct 20 14:03:51 2026`.
+/// OpenSIPS uses its local time, so `utc_offset` is that zone's offset from UTC in seconds.
+/// The epoch, which OpenSIPS prints for "never", gives `None`.
+pub fn parse_ctime(s: &str, utc_offset: i64) -> Option<SystemTime> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    let [_, month, day, time, year] = fields[..] else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let mut hms = time.split(':').map(|f| f.parse::<i64>());
+    let (Some(Ok(h)), Some(Ok(m)), Some(Ok(sec))) = (hms.next(), hms.next(), hms.next()) else {
+        return None;
+    };
+    let days = days_from_civil(year.parse().ok()?, month, day.parse().ok()?);
+    let seconds = days * 86400 + h * 3600 + m * 60 + sec - utc_offset;
+    (seconds > 0).then(|| UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+/// A `RegListRecord` with its dates, durations and binding parsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegistrantTiming {
+    pub last_register_sent: Option<SystemTime>,
+    /// when uac_registrant next sends a REGISTER, or gives up waiting for a reply
+    pub registration_timeout: Option<SystemTime>,
+    pub expires: Duration,
+    /// the Contact of the REGISTER, its URI parameters kept in `uri`; `None` when `binding`
+    /// doesn't parse
+    pub binding: Option<NameAddr>,
+    /// the Contact header parameters from `binding_params`
+    pub binding_params: Vec<(String, Option<String>)>,
+}
+
+impl RegistrantTiming {
+    /// Time left until the next REGISTER; zero when it is overdue.
+    pub fn next_refresh_in(&self, now: SystemTime) -> Option<Duration> {
+        let timeout = self.registration_timeout?;
+        Some(timeout.duration_since(now).unwrap_or(Duration::ZERO))
+    }
+}
+
+impl RegListRecord {
+    /// Parses the record. OpenSIPS prints its dates in the server's local time, which the record
+    /// doesn't carry, so `utc_offset` must be that zone's offset from UTC in seconds; pass 0
+    /// only when the server runs in UTC.
+    pub fn timing(&self, utc_offset: i64) -> RegistrantTiming {
+        // a bare addr-spec: any parameters belong to the URI
+        let binding = if self.binding.contains('<') {
+            NameAddr::parse(&self.binding)
+        } else {
+            NameAddr::parse(&format!("<{}>", self.binding.trim()))
+        };
+        RegistrantTiming {
+            last_register_sent: parse_ctime(&self.last_register_sent, utc_offset),
+            registration_timeout: parse_ctime(&self.registration_t_out, utc_offset),
+            expires: Duration::from_secs(self.expires),
+            binding,
+            binding_params: parse_params(&self.binding_params),
+        }
+    }
+}
+
+/// Identifies a registrant the way `reg_enable`/`reg_disable`/`reg_reload` do.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RegistrantKey {
+    pub aor: String,
+    pub contact: String,
+    pub registrar: String,
+}
+
+impl From<&RegListRecord> for RegistrantKey {
+    fn from(record: &RegListRecord) -> Self {
+        RegistrantKey { aor: record.aor.clone(), contact: record.binding.clone(), registrar: record.registrar.clone() }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RemediationAction {
+    /// `reg_disable` then `reg_enable`
+    Toggle,
+    /// `reg_reload` for just this record
+    Reload,
+}
+
+/// Remediate registrants that stay in one of `states` for longer than `after`, once per
+/// stretch in that state.
+#[derive(Clone, Debug)]
+pub struct RemediationPolicy {
+    pub states: Vec<RegState>,
+    pub after: Duration,
+    pub action: RemediationAction,
+}
+
+impl RemediationPolicy {
+    /// Applies to the error states: timeout, internal, credentials and registrar errors.
+    pub fn new(action: RemediationAction, after: Duration) -> Self {
+        RemediationPolicy {
+            states: vec![
+                RegState::RegisterTimeoutState,
+                RegState::InternalErrorState,
+                RegState::WrongCredentialsState,
+                RegState::RegistrarErrorState,
+            ],
+            after,
+            action,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegistrantEvent {
+    Added { key: RegistrantKey, state: RegState },
+    Removed { key: RegistrantKey },
+    Changed { key: RegistrantKey, from: RegState, to: RegState },
+    /// The registrant has been in `state` for longer than its threshold.
+    Stuck { key: RegistrantKey, state: RegState, duration: Duration },
+    Remediated { key: RegistrantKey, action: RemediationAction },
+    RemediationFailed { key: RegistrantKey, action: RemediationAction, error: String },
+    PollFailed { error: String },
+}
+
+#[derive(Clone, Debug)]
+struct Tracked {
+    record: RegListRecord,
+    since: Instant,
+    alarmed: bool,
+    remediated: bool,
+}
+
+/// Polls `reg_list` and turns what changed between polls into events, since OpenSIPS doesn't
+/// raise any for registrants.
+pub struct RegistrantMonitor<C> {
+    client: C,
+    poll_interval: Duration,
+    thresholds: Vec<(RegState, Duration)>,
+    policy: Option<RemediationPolicy>,
+    events: mpsc::Sender<RegistrantEvent>,
+    registrants: BTreeMap<RegistrantKey, Tracked>,
+    polled: bool,
+}
+
+impl<C> RegistrantMonitor<C>
+    where C: OpenSIPSClient + Sync
+{
+    pub fn new(client: C, events: mpsc::Sender<RegistrantEvent>) -> Self {
+        RegistrantMonitor {
+            client,
+            poll_interval: Duration::from_secs(30),
+            thresholds: Vec::new(),
+            policy: None,
+            events,
+            registrants: BTreeMap::new(),
+            polled: false,
+        }
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Report registrants that stay in `state` for longer than `duration`.
+    pub fn threshold(mut self, state: RegState, duration: Duration) -> Self {
+        self.thresholds.push((state, duration));
+        self
+    }
+
+    pub fn remediation(mut self, policy: RemediationPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// The last polled record of each registrant.
+    pub fn registrants(&self) -> impl Iterator<Item = &RegListRecord> {
+        self.registrants.values().map(|t| &t.record)
+    }
+
+    /// Fetches `reg_list` once and reports what changed. The first poll only reports `Added`.
+    pub async fn poll(&mut self) -> Vec<RegistrantEvent> {
+        let records = match self.client.reg_list().await {
+            Ok(response) => response.records,
+            Err(e) => {
+                warn!("reg_list failed: {}", e);
+                let event = RegistrantEvent::PollFailed { error: e.to_string() };
+                self.emit(&event).await;
+                return vec![event];
+            }
+        };
+        let now = Instant::now();
+        let mut events = Vec::new();
+        let mut seen = BTreeMap::new();
+        for record in records {
+            let key = RegistrantKey::from(&record);
+            let tracked = match self.registrants.remove(&key) {
+                Some(tracked) if tracked.record.state == record.state => Tracked { record, ..tracked },
+                Some(tracked) => {
+                    events.push(RegistrantEvent::Changed { key: key.clone(), from: tracked.record.state, to: record.state.clone() });
+                    Tracked { record, since: now, alarmed: false, remediated: false }
+                }
+                None => {
+                    if self.polled {
+                        events.push(RegistrantEvent::Added { key: key.clone(), state: record.state.clone() });
+                    }
+                    Tracked { record, since: now, alarmed: false, remediated: false }
+                }
+            };
+            seen.insert(key, tracked);
+        }
+        events.extend(self.registrants.keys().map(|key| RegistrantEvent::Removed { key: key.clone() }));
+        if !self.polled {
+            events.extend(seen.iter().map(|(key, t)| RegistrantEvent::Added { key: key.clone(), state: t.record.state.clone() }));
+        }
+        self.registrants = seen;
+        self.polled = true;
+
+        for (key, tracked) in self.registrants.iter_mut() {
+            let duration = now.duration_since(tracked.since);
+            let over = self.thresholds.iter()
+                .any(|(state, threshold)| *state == tracked.record.state && duration > *threshold);
+            if over && !tracked.alarmed {
+                tracked.alarmed = true;
+                events.push(RegistrantEvent::Stuck { key: key.clone(), state: tracked.record.state.clone(), duration });
+            }
+        }
+        events.extend(self.remediate(now).await);
+
+        for event in &events {
+            self.emit(event).await;
+        }
+        events
+    }
+
+    async fn remediate(&mut self, now: Instant) -> Vec<RegistrantEvent> {
+        let Some(policy) = &self.policy else {
+            return Vec::new();
+        };
+        let mut events = Vec::new();
+        for (key, tracked) in self.registrants.iter_mut() {
+            if tracked.remediated
+                || !policy.states.contains(&tracked.record.state)
+                || now.duration_since(tracked.since) <= policy.after {
+                continue;
+            }
+            tracked.remediated = true;
+            let (aor, contact, registrar) = (key.aor.clone(), key.contact.clone(), key.registrar.clone());
+            let result = match policy.action {
+                RemediationAction::Toggle => match self.client.reg_disable(aor.clone(), contact.clone(), registrar.clone()).await {
+                    Ok(_) => self.client.reg_enable(aor, contact, registrar).await,
+                    Err(e) => Err(e),
+                },
+                RemediationAction::Reload => self.client.reg_reload_record(aor, contact, registrar).await,
+            };
+            events.push(match result {
+                Ok(_) => RegistrantEvent::Remediated { key: key.clone(), action: policy.action },
+                Err(e) => RegistrantEvent::RemediationFailed { key: key.clone(), action: policy.action, error: e.to_string() },
+            });
+        }
+        events
+    }
+
+    async fn emit(&self, event: &RegistrantEvent) {
+        debug!("registrant event: {:?}", event);
+        let _ = self.events.send(event.clone()).await;
+    }
+
+    /// Polls on the configured interval until the event receiver is dropped.
+    pub async fn run(mut self) {
+        let mut ticker = interval(self.poll_interval);
+        while !self.events.is_closed() {
+            ticker.tick().await;
+            self.poll().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::mock::MockOpenSIPS;
+
+    fn record(aor: &str, state: RegState) -> RegListRecord {
+        RegListRecord {
+            aor: aor.into(),
+            binding: "sip:trunk@10.0.0.5".into(),
+            registrar: "sip:registrar.example.com".into(),
+            state,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transitions_and_remediation() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        let records = Arc::new(Mutex::new(vec![record("sip:a@example.com", RegState::RegisteredState)]));
+        let listed = records.clone();
+        mock.respond_with("reg_list", move |_| Ok(serde_json::to_value(RegListResponse { records: listed.lock().unwrap().clone() }).unwrap()));
+        mock.respond("reg_disable", "OK");
+        mock.respond("reg_enable", "OK");
+
+        let (tx, _rx) = mpsc::channel(64);
+        let mut monitor = RegistrantMonitor::new(mock.client(), tx)
+            .threshold(RegState::WrongCredentialsState, Duration::ZERO)
+            .remediation(RemediationPolicy::new(RemediationAction::Toggle, Duration::ZERO));
+        let a = RegistrantKey::from(&record("sip:a@example.com", RegState::RegisteredState));
+        assert_eq!(monitor.poll().await, vec![RegistrantEvent::Added { key: a.clone(), state: RegState::RegisteredState }]);
+
+        *records.lock().unwrap() = vec![record("sip:a@example.com", RegState::WrongCredentialsState)];
+        assert_eq!(monitor.poll().await, vec![
+            RegistrantEvent::Changed { key: a.clone(), from: RegState::RegisteredState, to: RegState::WrongCredentialsState },
+        ]);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let events = monitor.poll().await;
+        assert!(matches!(&events[0], RegistrantEvent::Stuck { state: RegState::WrongCredentialsState, .. }));
+        assert_eq!(events[1], RegistrantEvent::Remediated { key: a.clone(), action: RemediationAction::Toggle });
+        assert_eq!(mock.calls("reg_disable").len(), 1);
+        assert_eq!(mock.calls("reg_enable")[0]["contact"], "sip:trunk@10.0.0.5");
+
+        // alarms and remediation happen once per stretch in the state
+        assert!(monitor.poll().await.is_empty());
+        records.lock().unwrap().clear();
+        assert_eq!(monitor.poll().await, vec![RegistrantEvent::Removed { key: a }]);
+    }
+
+    #[test]
+    fn test_timing() {
+        assert_eq!(parse_ctime("Thu Jan  1 00:00:00 1970", 0), None);
+        assert_eq!(parse_ctime("Sat Feb 29 12:00:00 2020", 0), Some(UNIX_EPOCH + Duration::from_secs(1582977600)));
+        assert_eq!(parse_ctime("Sat Feb 29 14:00:00 2020", 7200), Some(UNIX_EPOCH + Duration::from_secs(1582977600)));
+        assert_eq!(parse_ctime("yesterday", 0), None);
+
+        let record = RegListRecord {
+            expires: 3600,
+            last_register_sent: "Mon Oct 19 10:00:00 2026".into(),
+            registration_t_out: "Mon Oct 19 10:59:50 2026".into(),
+            binding: "sip:trunk@10.0.0.5:5060;transport=tcp".into(),
+            binding_params: "ob;+sip.instance=\"<urn:uuid:1;a=\\\"b\\\">\";reg-id=1".into(),
+            ..Default::default()
+        };
+        let timing = record.timing(0);
+        assert_eq!(timing.expires, Duration::from_secs(3600));
+        let binding = timing.binding.as_ref().unwrap();
+        assert_eq!((binding.uri.as_str(), binding.params.len()), ("sip:trunk@10.0.0.5:5060;transport=tcp", 0));
+        assert_eq!(timing.binding_params, vec![
+            ("ob".to_string(), None),
+            ("+sip.instance".to_string(), Some("\"<urn:uuid:1;a=\\\"b\\\">\"".to_string())),
+            ("reg-id".to_string(), Some("1".to_string())),
+        ]);
+        let sent = timing.last_register_sent.unwrap();
+        assert_eq!(timing.next_refresh_in(sent), Some(Duration::from_secs(3590)));
+        assert_eq!(timing.next_refresh_in(sent + Duration::from_secs(4000)), Some(Duration::ZERO));
+        // a server two hours ahead of UTC sent it two hours earlier
+        assert_eq!(record.timing(7200).last_register_sent, Some(sent - Duration::from_secs(7200)));
+        assert_eq!(RegListRecord { binding_params: "ob".into(), ..Default::default() }.timing(0).binding, None);
+    }
+}
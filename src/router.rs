@@ -0,0 +1,206 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use futures::future::{join_all, BoxFuture, FutureExt};
+use tracing::warn;
+use super::*;
+use super::dispatch::DispatchQueue;
+
+type Handler = Arc<dyn Fn(Notification) -> Option<BoxFuture<'static, Result<(), String>>> + Send + Sync>;
+
+/// A handler that returned an error or panicked.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandlerError {
+    pub event: &'static str,
+    /// position of the handler among those registered for the event
+    pub handler: usize,
+    pub error: String,
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} handler {} failed: {}", self.event, self.handler, self.error)
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// Fans notifications out to typed handlers. Every handler registered for an event runs in its
+/// own task, so one failing or panicking doesn't affect the others.
+#[derive(Clone, Default)]
+pub struct EventRouter {
+    handlers: Vec<(&'static str, Handler)>,
+    queue: DispatchQueue,
+}
+
+macro_rules! typed_handler {
+    ($(#[$doc:meta])* $name:ident, $event:literal, $variant:ident, $ty:ty) => {
+        $(#[$doc])*
+        pub fn $name<F, Fut, E>(self, f: F) -> Self
+            where F: Fn($ty) -> Fut + Send + Sync + 'static,
+                  Fut: Future<Output = Result<(), E>> + Send + 'static,
+                  E: fmt::Display
+        {
+            self.add($event, move |n| match n {
+                Notification::$variant(v) => Some(f(v)),
+                _ => None,
+            })
+        }
+    };
+}
+
+impl EventRouter {
+    pub fn new() -> Self {
+        EventRouter::default()
+    }
+
+    /// Bounds the background dispatch queue of `run`.
+    pub fn queue(mut self, queue: DispatchQueue) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    typed_handler!(on_contact_insert, "E_UL_CONTACT_INSERT", EUlContactInsert, UlContact);
+    typed_handler!(on_contact_delete, "E_UL_CONTACT_DELETE", EUlContactDelete, UlContact);
+    typed_handler!(on_contact_update, "E_UL_CONTACT_UPDATE", EUlContactUpdate, UlContact);
+    typed_handler!(on_dialog_change, "E_DLG_STATE_CHANGED", EDlgStateChanged, DialogChange);
+    typed_handler!(on_dispatcher_status, "E_DISPATCHER_STATUS", EDispatcherStatus, DispatcherStatus);
+    typed_handler!(on_node_state_change, "E_CLUSTERER_NODE_STATE_CHANGE", EClustererNodeStateChange, ClustererNodeStateChange);
+    typed_handler!(on_cluster_request, "E_CLUSTERER_REQ_RECEIVED", EClustererReqReceived, ClustererMessage);
+    typed_handler!(on_cluster_reply, "E_CLUSTERER_RPL_RECEIVED", EClustererRplReceived, ClustererMessage);
+    typed_handler!(on_ua_session, "E_UA_SESSION", EUaSession, UASession);
+
+    fn add<F, Fut, E>(mut self, event: &'static str, f: F) -> Self
+        where F: Fn(Notification) -> Option<Fut> + Send + Sync + 'static,
+              Fut: Future<Output = Result<(), E>> + Send + 'static,
+              E: fmt::Display
+    {
+        let handler: Handler = Arc::new(move |n| {
+            f(n).map(|fut| fut.map(|r| r.map_err(|e| e.to_string())).boxed())
+        });
+        self.handlers.push((event, handler));
+        self
+    }
+
+    /// Event names with at least one handler, in registration order.
+    pub fn event_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = Vec::new();
+        for (name, _) in &self.handlers {
+            if !names.contains(name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Subscribes `socket` (e.g. `udp:10.0.0.5:10000`) to exactly the events handled here.
+    pub async fn subscribe<C>(&self, client: &C, socket: &str) -> Result<(), jsonrpsee::core::Error>
+        where C: OpenSIPSClient + Sync
+    {
+        for name in self.event_names() {
+            client.event_subscribe(name.to_string(), socket.to_string()).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs every handler for the notification concurrently and waits for all of them.
+    pub async fn dispatch(&self, notification: Notification) -> Vec<HandlerError> {
+        let event = notification.event_name();
+        let tasks: Vec<_> = self.handlers.iter()
+            .filter(|(name, _)| *name == event)
+            .enumerate()
+            .filter_map(|(i, (_, handler))| handler(notification.clone()).map(|fut| (i, tokio::spawn(fut))))
+            .collect();
+
+        let (indexes, handles): (Vec<_>, Vec<_>) = tasks.into_iter().unzip();
+        let mut errors = Vec::new();
+        for (handler, result) in indexes.into_iter().zip(join_all(handles).await) {
+            let error = match result {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => e,
+                Err(e) => format!("handler task failed: {}", e),
+            };
+            let error = HandlerError { event, handler, error };
+            warn!("{}", error);
+            errors.push(error);
+        }
+        errors
+    }
+
+    /// Feeds the receiver into this router until the socket fails. Notifications are dispatched
+    /// in the background so that slow handlers don't hold up the socket.
+    pub async fn run(self, receiver: UdpNotificationReceiver) -> tokio::io::Result<()> {
+        let queue = self.queue;
+        let router = Arc::new(self);
+        receiver.run_queued(queue, move |notification| {
+            let router = router.clone();
+            async move {
+                router.dispatch(notification).await;
+            }
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_dispatch_isolates_failures() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_ok = seen.clone();
+        let router = EventRouter::new()
+            .on_dispatcher_status(move |s: DispatcherStatus| {
+                let seen = seen_ok.clone();
+                async move {
+                    seen.lock().unwrap().push(s.address);
+                    Ok::<_, String>(())
+                }
+            })
+            .on_dispatcher_status(|_| async { Err("boom") })
+            .on_dispatcher_status(|_| async { panic!("handler panic") as Result<(), String> })
+            .on_dialog_change(|_| async { Ok::<_, String>(()) });
+
+        assert_eq!(router.event_names(), vec!["E_DISPATCHER_STATUS", "E_DLG_STATE_CHANGED"]);
+
+        let status = DispatcherStatus {
+            partition: "default".into(),
+            group: "1".into(),
+            address: "sip:10.0.0.1".into(),
+            status: DispatcherState::Active,
+        };
+        let errors = router.dispatch(Notification::EDispatcherStatus(status)).await;
+        assert_eq!(*seen.lock().unwrap(), vec!["sip:10.0.0.1".to_string()]);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], HandlerError { event: "E_DISPATCHER_STATUS", handler: 1, error: "boom".into() });
+        assert_eq!(errors[1].handler, 2);
+    }
+
+    #[tokio::test]
+    async fn test_run_doesnt_wait_for_handlers() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let router = EventRouter::new().on_node_state_change(move |c: ClustererNodeStateChange| {
+            let tx = tx.clone();
+            async move {
+                // the first notification never finishes its handler
+                if c.node_id == 1 {
+                    std::future::pending::<()>().await;
+                }
+                tx.send(c.node_id).map_err(|e| e.to_string())
+            }
+        });
+        tokio::spawn(router.run(UdpNotificationReceiver::new(socket)));
+
+        let sender = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for node_id in 1..=2 {
+            let payload = format!(r#"{{"jsonrpc":"2.0","method":"E_CLUSTERER_NODE_STATE_CHANGE","params":{{"cluster_id":1,"node_id":{},"new_state":1}}}}"#, node_id);
+            sender.send_to(payload.as_bytes(), addr).await.unwrap();
+        }
+        let handled = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert_eq!(handled, Some(2));
+    }
+}
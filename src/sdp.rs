@@ -0,0 +1,593 @@
+//! SDP bodies, kept line by line so an unmodified body prints exactly as it was parsed.
+use std::fmt;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SdpError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SdpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid SDP at line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+/// Whether a Content-Type header value denotes SDP.
+pub fn is_sdp(content_type: &str) -> bool {
+    content_type.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/sdp")
+}
+
+/// One `<type>=<value>` line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub kind: char,
+    pub value: String,
+}
+
+impl Line {
+    pub fn new(kind: char, value: impl Into<String>) -> Self {
+        Line { kind, value: value.into() }
+    }
+
+    /// For `a=` lines, the attribute name and its value if it has one.
+    pub fn attribute(&self) -> Option<(&str, Option<&str>)> {
+        if self.kind != 'a' {
+            return None;
+        }
+        Some(match self.value.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (self.value.as_str(), None),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub username: String,
+    pub session_id: String,
+    pub session_version: u64,
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+impl FromStr for Origin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let f: Vec<&str> = s.split_whitespace().collect();
+        let [username, session_id, version, net_type, addr_type, address] = f[..] else {
+            return Err(format!("expected 6 origin fields, got {}", f.len()));
+        };
+        Ok(Origin {
+            username: username.to_string(),
+            session_id: session_id.to_string(),
+            session_version: version.parse().map_err(|_| format!("bad session version {}", version))?,
+            net_type: net_type.to_string(),
+            addr_type: addr_type.to_string(),
+            address: address.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {} {} {}", self.username, self.session_id, self.session_version, self.net_type, self.addr_type, self.address)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Connection {
+    pub net_type: String,
+    pub addr_type: String,
+    pub address: String,
+}
+
+impl Connection {
+    pub fn ip4(address: &str) -> Self {
+        Connection { net_type: "IN".to_string(), addr_type: "IP4".to_string(), address: address.to_string() }
+    }
+
+    pub fn ip6(address: &str) -> Self {
+        Connection { net_type: "IN".to_string(), addr_type: "IP6".to_string(), address: address.to_string() }
+    }
+
+    /// `IP6` for IPv6 addresses, `IP4` for anything else, host names included.
+    pub fn ip(address: &str) -> Self {
+        match address.parse::<Ipv6Addr>() {
+            Ok(_) => Connection::ip6(address),
+            Err(_) => Connection::ip4(address),
+        }
+    }
+}
+
+impl FromStr for Connection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let f: Vec<&str> = s.split_whitespace().collect();
+        let [net_type, addr_type, address] = f[..] else {
+            return Err(format!("expected 3 connection fields, got {}", f.len()));
+        };
+        Ok(Connection { net_type: net_type.to_string(), addr_type: addr_type.to_string(), address: address.to_string() })
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.net_type, self.addr_type, self.address)
+    }
+}
+
+/// The `m=` line itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaLine {
+    pub media: String,
+    pub port: u16,
+    pub proto: String,
+    pub formats: Vec<String>,
+}
+
+impl FromStr for MediaLine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut f = s.split_whitespace();
+        let (Some(media), Some(port), Some(proto)) = (f.next(), f.next(), f.next()) else {
+            return Err("expected media, port and proto".to_string());
+        };
+        // a port count (port/2) is accepted but not kept
+        let port = port.split('/').next().unwrap_or(port);
+        Ok(MediaLine {
+            media: media.to_string(),
+            port: port.parse().map_err(|_| format!("bad port {}", port))?,
+            proto: proto.to_string(),
+            formats: f.map(str::to_string).collect(),
+        })
+    }
+}
+
+impl fmt::Display for MediaLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.media, self.port, self.proto)?;
+        for format in &self.formats {
+            write!(f, " {}", format)?;
+        }
+        Ok(())
+    }
+}
+
+/// A payload type with what `a=rtpmap` and `a=fmtp` say about it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Codec {
+    pub payload_type: u8,
+    pub name: String,
+    pub clock_rate: u32,
+    pub channels: Option<u32>,
+    pub fmtp: Option<String>,
+}
+
+impl Codec {
+    pub fn new(payload_type: u8, name: &str, clock_rate: u32) -> Self {
+        Codec { payload_type, name: name.to_string(), clock_rate, channels: None, fmtp: None }
+    }
+
+    pub fn pcmu() -> Self {
+        Codec::new(0, "PCMU", 8000)
+    }
+
+    pub fn pcma() -> Self {
+        Codec::new(8, "PCMA", 8000)
+    }
+
+    pub fn telephone_event() -> Self {
+        Codec { fmtp: Some("0-16".to_string()), ..Codec::new(101, "telephone-event", 8000) }
+    }
+
+    fn rtpmap(&self) -> String {
+        match self.channels {
+            Some(channels) => format!("{} {}/{}/{}", self.payload_type, self.name, self.clock_rate, channels),
+            None => format!("{} {}/{}", self.payload_type, self.name, self.clock_rate),
+        }
+    }
+
+    // static payload types that may be used without an rtpmap
+    fn from_static(payload_type: u8) -> Option<Self> {
+        match payload_type {
+            0 => Some(Codec::pcmu()),
+            8 => Some(Codec::pcma()),
+            3 => Some(Codec::new(3, "GSM", 8000)),
+            9 => Some(Codec::new(9, "G722", 8000)),
+            18 => Some(Codec::new(18, "G729", 8000)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    SendRecv,
+    SendOnly,
+    RecvOnly,
+    Inactive,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::SendRecv => "sendrecv",
+            Direction::SendOnly => "sendonly",
+            Direction::RecvOnly => "recvonly",
+            Direction::Inactive => "inactive",
+        }
+    }
+
+    fn from_attribute(name: &str) -> Option<Self> {
+        match name {
+            "sendrecv" => Some(Direction::SendRecv),
+            "sendonly" => Some(Direction::SendOnly),
+            "recvonly" => Some(Direction::RecvOnly),
+            "inactive" => Some(Direction::Inactive),
+            _ => None,
+        }
+    }
+
+    /// What the answerer uses for an offer with this direction.
+    pub fn reverse(&self) -> Self {
+        match self {
+            Direction::SendOnly => Direction::RecvOnly,
+            Direction::RecvOnly => Direction::SendOnly,
+            other => *other,
+        }
+    }
+
+    /// The direction for putting the peer on hold, so it stops sending to us (RFC 6337).
+    pub fn held(&self) -> Self {
+        match self {
+            Direction::SendRecv | Direction::SendOnly => Direction::SendOnly,
+            Direction::RecvOnly | Direction::Inactive => Direction::Inactive,
+        }
+    }
+
+    pub fn resumed(&self) -> Self {
+        match self {
+            Direction::SendOnly | Direction::SendRecv => Direction::SendRecv,
+            Direction::Inactive | Direction::RecvOnly => Direction::RecvOnly,
+        }
+    }
+}
+
+fn find(lines: &[Line], kind: char) -> Option<&Line> {
+    lines.iter().find(|l| l.kind == kind)
+}
+
+fn direction_in(lines: &[Line]) -> Option<Direction> {
+    lines.iter()
+        .filter_map(Line::attribute)
+        .find_map(|(name, _)| Direction::from_attribute(name))
+}
+
+/// One `m=` line and everything up to the next.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaSection {
+    /// starts with the `m=` line
+    pub lines: Vec<Line>,
+}
+
+impl MediaSection {
+    pub fn new(media: &MediaLine) -> Self {
+        MediaSection { lines: vec![Line::new('m', media.to_string())] }
+    }
+
+    pub fn media(&self) -> Option<MediaLine> {
+        self.lines.first()?.value.parse().ok()
+    }
+
+    pub fn set_port(&mut self, port: u16) {
+        if let Some(mut media) = self.media() {
+            media.port = port;
+            self.lines[0].value = media.to_string();
+        }
+    }
+
+    pub fn connection(&self) -> Option<Connection> {
+        find(&self.lines, 'c')?.value.parse().ok()
+    }
+
+    pub fn attributes(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.lines.iter().filter_map(Line::attribute)
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes().find(|(n, _)| *n == name).map(|(_, v)| v.unwrap_or(""))
+    }
+
+    /// Codecs in `m=` line order, combining `a=rtpmap` and `a=fmtp`.
+    pub fn codecs(&self) -> Vec<Codec> {
+        let Some(media) = self.media() else {
+            return Vec::new();
+        };
+        media.formats.iter()
+            .filter_map(|f| f.parse::<u8>().ok())
+            .filter_map(|pt| {
+                let prefix = format!("{} ", pt);
+                let for_pt = |attr: &str| self.attributes()
+                    .find(|(n, v)| *n == attr && v.is_some_and(|v| v.starts_with(&prefix)))
+                    .and_then(|(_, v)| v)
+                    .map(|v| v[prefix.len()..].trim());
+                let mut codec = match for_pt("rtpmap") {
+                    Some(map) => {
+                        let mut parts = map.split('/');
+                        Codec {
+                            payload_type: pt,
+                            name: parts.next()?.to_string(),
+                            clock_rate: parts.next()?.parse().ok()?,
+                            channels: parts.next().and_then(|c| c.parse().ok()),
+                            fmtp: None,
+                        }
+                    }
+                    None => Codec::from_static(pt)?,
+                };
+                codec.fmtp = for_pt("fmtp").map(str::to_string);
+                Some(codec)
+            })
+            .collect()
+    }
+
+    /// The media's own direction attribute, if any.
+    pub fn direction(&self) -> Option<Direction> {
+        direction_in(&self.lines)
+    }
+
+    /// Replaces the direction attribute, adding one if there is none.
+    pub fn set_direction(&mut self, direction: Direction) {
+        let line = Line::new('a', direction.as_str());
+        match self.lines.iter().position(|l| l.attribute().is_some_and(|(n, _)| Direction::from_attribute(n).is_some())) {
+            Some(i) => self.lines[i] = line,
+            None => self.lines.push(line),
+        }
+    }
+}
+
+/// A parsed SDP body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sdp {
+    /// session level lines, from `v=` up to the first `m=`
+    pub session: Vec<Line>,
+    pub media: Vec<MediaSection>,
+    line_ending: &'static str,
+    trailing_line_ending: bool,
+}
+
+impl FromStr for Sdp {
+    type Err = SdpError;
+
+    fn from_str(s: &str) -> Result<Self, SdpError> {
+        let line_ending = if s.contains("\r\n") { "\r\n" } else { "\n" };
+        let trailing_line_ending = s.ends_with('\n');
+        let body = s.strip_suffix(line_ending).unwrap_or(s);
+        let mut sdp = Sdp { session: Vec::new(), media: Vec::new(), line_ending, trailing_line_ending };
+        for (i, text) in body.split(line_ending).enumerate() {
+            // some bodies end in a blank line
+            if text.trim().is_empty() {
+                continue;
+            }
+            let error = |message: &str| SdpError { line: i + 1, message: message.to_string() };
+            let mut chars = text.chars();
+            let (Some(kind), Some('=')) = (chars.next(), chars.next()) else {
+                return Err(error("expected <type>=<value>"));
+            };
+            if !kind.is_ascii() {
+                return Err(error("type must be a single ASCII character"));
+            }
+            let line = Line::new(kind, &text[2..]);
+            if kind == 'm' {
+                line.value.parse::<MediaLine>().map_err(|e| error(&e))?;
+                sdp.media.push(MediaSection { lines: vec![line] });
+            } else if let Some(media) = sdp.media.last_mut() {
+                media.lines.push(line);
+            } else {
+                sdp.session.push(line);
+            }
+        }
+        if sdp.session.first().is_none_or(|l| l.kind != 'v') {
+            return Err(SdpError { line: 1, message: "must start with v=".to_string() });
+        }
+        Ok(sdp)
+    }
+}
+
+impl fmt::Display for Sdp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lines: Vec<&Line> = self.session.iter().chain(self.media.iter().flat_map(|m| &m.lines)).collect();
+        for (i, line) in lines.iter().enumerate() {
+            write!(f, "{}={}", line.kind, line.value)?;
+            if i + 1 < lines.len() || self.trailing_line_ending {
+                f.write_str(self.line_ending)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sdp {
+    /// An offer with one section per entry in `media`, all on `address`.
+    pub fn offer(origin: Origin, address: &str, media: Vec<(MediaLine, Vec<Codec>)>) -> Self {
+        let mut sdp = Sdp {
+            session: vec![
+                Line::new('v', "0"),
+                Line::new('o', origin.to_string()),
+                Line::new('s', "-"),
+                Line::new('c', Connection::ip(address).to_string()),
+                Line::new('t', "0 0"),
+            ],
+            media: Vec::new(),
+            line_ending: "\r\n",
+            trailing_line_ending: true,
+        };
+        for (mut line, codecs) in media {
+            line.formats = codecs.iter().map(|c| c.payload_type.to_string()).collect();
+            let mut section = MediaSection::new(&line);
+            for codec in &codecs {
+                section.lines.push(Line::new('a', format!("rtpmap:{}", codec.rtpmap())));
+                if let Some(fmtp) = &codec.fmtp {
+                    section.lines.push(Line::new('a', format!("fmtp:{} {}", codec.payload_type, fmtp)));
+                }
+            }
+            section.set_direction(Direction::SendRecv);
+            sdp.media.push(section);
+        }
+        sdp
+    }
+
+    /// Answers `offer`, keeping the offered codecs whose names are in `supported` (in offer
+    /// order) and rejecting streams with none, per RFC 3264. `ports` are used in turn for
+    /// the accepted streams.
+    pub fn answer(offer: &Sdp, origin: Origin, address: &str, ports: &[u16], supported: &[&str]) -> Result<Self, SdpError> {
+        let mut ports = ports.iter();
+        let mut line_number = offer.session.len() + 1;
+        let media = offer.media.iter()
+            .map(|offered| {
+                let mut line = offered.media()
+                    .ok_or_else(|| SdpError { line: line_number, message: "media section without a valid m= line".to_string() })?;
+                line_number += offered.lines.len();
+                let codecs: Vec<Codec> = offered.codecs().into_iter()
+                    .filter(|c| supported.iter().any(|s| s.eq_ignore_ascii_case(&c.name)))
+                    .collect();
+                line.port = match (line.port, codecs.is_empty()) {
+                    (0, _) | (_, true) => 0,
+                    _ => ports.next().copied().unwrap_or(0),
+                };
+                let direction = offered.direction().or(offer.direction()).unwrap_or_default().reverse();
+                Ok((line, codecs, direction))
+            })
+            .collect::<Result<Vec<_>, SdpError>>()?;
+
+        let mut answer = Sdp::offer(origin, address, media.iter().map(|(l, c, _)| (l.clone(), c.clone())).collect());
+        for (section, (line, codecs, direction)) in answer.media.iter_mut().zip(media) {
+            if codecs.is_empty() {
+                // a rejected stream still needs a format
+                *section = MediaSection::new(&MediaLine { port: 0, formats: line.formats.iter().take(1).cloned().collect(), ..line });
+            } else {
+                section.set_direction(direction);
+            }
+        }
+        Ok(answer)
+    }
+
+    pub fn origin(&self) -> Option<Origin> {
+        find(&self.session, 'o')?.value.parse().ok()
+    }
+
+    pub fn connection(&self) -> Option<Connection> {
+        find(&self.session, 'c')?.value.parse().ok()
+    }
+
+    /// Where the stream's media goes: its own `c=` line, or the session's.
+    pub fn media_connection(&self, index: usize) -> Option<Connection> {
+        self.media.get(index)?.connection().or_else(|| self.connection())
+    }
+
+    /// The session level direction attribute, if any.
+    pub fn direction(&self) -> Option<Direction> {
+        direction_in(&self.session)
+    }
+
+    /// Effective direction of one stream.
+    pub fn media_direction(&self, index: usize) -> Direction {
+        self.media.get(index)
+            .and_then(MediaSection::direction)
+            .or(self.direction())
+            .unwrap_or_default()
+    }
+
+    /// Increments the origin's session version, as any change to an offer requires.
+    pub fn bump_version(&mut self) {
+        if let Some(i) = self.session.iter().position(|l| l.kind == 'o') {
+            if let Ok(mut origin) = self.session[i].value.parse::<Origin>() {
+                origin.session_version += 1;
+                self.session[i].value = origin.to_string();
+            }
+        }
+    }
+
+    /// Puts one stream, or all of them with `None`, on hold.
+    pub fn hold(&mut self, index: Option<usize>) {
+        self.redirect(index, Direction::held);
+    }
+
+    pub fn resume(&mut self, index: Option<usize>) {
+        self.redirect(index, Direction::resumed);
+    }
+
+    fn redirect(&mut self, index: Option<usize>, f: fn(&Direction) -> Direction) {
+        for i in 0..self.media.len() {
+            if index.is_none_or(|index| index == i) {
+                let direction = f(&self.media_direction(i));
+                self.media[i].set_direction(direction);
+            }
+        }
+        self.bump_version();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\no=alice 2890844526 2890844526 IN IP4 host.atlanta.example.com\r\ns=\r\nc=IN IP4 192.0.2.10\r\nt=0 0\r\nm=audio 49170 RTP/AVP 0 8 97 101\r\na=rtpmap:0 PCMU/8000\r\na=rtpmap:97 iLBC/8000\r\na=fmtp:97 mode=30\r\na=rtpmap:101 telephone-event/8000\r\na=sendonly\r\nm=video 51372 RTP/AVP 31\r\na=rtpmap:31 H261/90000\r\n";
+
+    #[test]
+    fn test_round_trip_and_answer() {
+        let mut sdp: Sdp = OFFER.parse().unwrap();
+        assert_eq!(sdp.to_string(), OFFER);
+        assert_eq!(sdp.origin().unwrap().session_version, 2890844526);
+        assert_eq!(sdp.media_connection(1).unwrap().address, "192.0.2.10");
+        let codecs = sdp.media[0].codecs();
+        assert_eq!(codecs.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), vec!["PCMU", "PCMA", "iLBC", "telephone-event"]);
+        assert_eq!(codecs[2].fmtp.as_deref(), Some("mode=30"));
+        assert_eq!(sdp.media_direction(0), Direction::SendOnly);
+        assert_eq!(sdp.media_direction(1), Direction::SendRecv);
+
+        let origin: Origin = "bob 1 1 IN IP4 192.0.2.20".parse().unwrap();
+        let answer = Sdp::answer(&sdp, origin.clone(), "192.0.2.20", &[4000, 4002], &["PCMA", "telephone-event"]).unwrap();
+        let reparsed: Sdp = answer.to_string().parse().unwrap();
+        assert_eq!(reparsed, answer);
+        assert_eq!(answer.media[0].media().unwrap().formats, vec!["8", "101"]);
+        assert_eq!(answer.media[0].media().unwrap().port, 4000);
+        assert_eq!(answer.media_direction(0), Direction::RecvOnly);
+        assert_eq!(answer.media[1].media().unwrap().port, 0);
+
+        sdp.resume(Some(0));
+        sdp.hold(Some(1));
+        assert_eq!((sdp.media_direction(0), sdp.media_direction(1)), (Direction::SendRecv, Direction::SendOnly));
+        assert_eq!(sdp.origin().unwrap().session_version, 2890844528);
+        assert!("o=x\r\n".parse::<Sdp>().is_err());
+        assert!("v=0\r\n€=x\r\n".parse::<Sdp>().is_err());
+        assert_eq!(format!("{}\r\n", OFFER).parse::<Sdp>().unwrap().media.len(), 2);
+
+        // an offer changed by hand can't be answered, rather than panicking
+        sdp.media[1].lines[0] = Line::new('a', "sendrecv");
+        let error = Sdp::answer(&sdp, origin.clone(), "192.0.2.20", &[4000], &["PCMU"]).unwrap_err();
+        assert_eq!(error.line, 12);
+
+        let offer = Sdp::offer(origin, "2001:db8::20", vec![("audio 4000 RTP/AVP 0".parse().unwrap(), sdp.media[0].codecs())]);
+        assert_eq!(offer.connection(), Some(Connection::ip6("2001:db8::20")));
+    }
+
+    #[test]
+    fn test_answer_skips_rejected_streams() {
+        let offer: Sdp = "v=0\r\no=alice 1 1 IN IP4 192.0.2.10\r\ns=-\r\nc=IN IP4 192.0.2.10\r\nt=0 0\r\nm=audio 49170 RTP/AVP 8\r\nm=video 51372 RTP/AVP 31\r\nm=audio 49172 RTP/AVP 0\r\n"
+            .parse().unwrap();
+        let origin: Origin = "bob 1 1 IN IP4 192.0.2.20".parse().unwrap();
+        let answer = Sdp::answer(&offer, origin, "192.0.2.20", &[4000, 4002], &["PCMA", "PCMU"]).unwrap();
+        let ports: Vec<u16> = answer.media.iter().map(|m| m.media().unwrap().port).collect();
+        assert_eq!(ports, vec![4000, 0, 4002]);
+    }
+}
@@ -0,0 +1,532 @@
+//! Bits of SIP syntax that show up in MI replies and event payloads.
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use serde_derive::{Deserialize, Serialize};
+
+/// A `name-addr` or `addr-spec` as found in From, To and Contact, e.g.
+/// `"Alice" <sip:alice@example.com>;tag=1928301774`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NameAddr {
+    pub display_name: Option<String>,
+    pub uri: String,
+    /// header parameters after the URI, in order; flags like `;lr` have no value
+    pub params: Vec<(String, Option<String>)>,
+}
+
+/// Splits `;name=value;flag` parameters; flags get `None`. A `;` inside a
+/// quoted value, as in `+sip.instance="<urn:uuid:a;b>"`, doesn't split.
+pub fn parse_params(s: &str) -> Vec<(String, Option<String>)> {
+    let (mut quoted, mut escaped) = (false, false);
+    let separator = |c| {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => return true,
+            _ => {}
+        }
+        false
+    };
+    s.split(separator)
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.split_once('=') {
+            Some((k, v)) => (k.trim().to_string(), Some(v.trim().to_string())),
+            None => (p.to_string(), None),
+        })
+        .collect()
+}
+
+impl NameAddr {
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (display_name, uri, rest) = match s.find('<') {
+            Some(start) => {
+                let end = start + s[start..].find('>')?;
+                let display = s[..start].trim().trim_matches('"');
+                let display_name = (!display.is_empty()).then(|| display.to_string());
+                (display_name, &s[start + 1..end], &s[end + 1..])
+            }
+            // without brackets, parameters belong to the header, not the URI
+            None => match s.split_once(';') {
+                Some((uri, rest)) => (None, uri, rest),
+                None => (None, s, ""),
+            },
+        };
+        if uri.trim().is_empty() {
+            return None;
+        }
+        Some(NameAddr { display_name, uri: uri.trim().to_string(), params: parse_params(rest) })
+    }
+
+    /// Value of a header parameter; flags give `Some("")`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_deref().unwrap_or(""))
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.param("tag")
+    }
+}
+
+impl fmt::Display for NameAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(display_name) = &self.display_name {
+            write!(f, "\"{}\" ", display_name)?;
+        }
+        write!(f, "<{}>", self.uri)?;
+        for (k, v) in &self.params {
+            match v {
+                Some(v) => write!(f, ";{}={}", k, v)?,
+                None => write!(f, ";{}", k)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compact form letter and full name, RFC 3261 7.3.3 and later extensions.
+const COMPACT_FORMS: &[(&str, &str)] = &[
+    ("a", "Accept-Contact"),
+    ("b", "Referred-By"),
+    ("c", "Content-Type"),
+    ("d", "Request-Disposition"),
+    ("e", "Content-Encoding"),
+    ("f", "From"),
+    ("i", "Call-ID"),
+    ("j", "Reject-Contact"),
+    ("k", "Supported"),
+    ("l", "Content-Length"),
+    ("m", "Contact"),
+    ("o", "Event"),
+    ("r", "Refer-To"),
+    ("s", "Subject"),
+    ("t", "To"),
+    ("u", "Allow-Events"),
+    ("v", "Via"),
+    ("x", "Session-Expires"),
+    ("y", "Identity"),
+];
+
+// headers whose values contain commas that don't separate values
+const SINGLE_VALUED: &[&str] = &[
+    "authorization", "proxy-authorization", "www-authenticate", "proxy-authenticate", "date",
+    "subject", "user-agent", "server", "organization", "call-id", "cseq", "content-length",
+    "content-type", "from", "to", "max-forwards", "expires", "replaces", "identity",
+];
+
+/// The full name for a compact form, otherwise the name as given.
+pub fn expand_header_name(name: &str) -> &str {
+    COMPACT_FORMS.iter()
+        .find(|(compact, _)| compact.eq_ignore_ascii_case(name))
+        .map_or(name, |(_, full)| full)
+}
+
+fn same_header(a: &str, b: &str) -> bool {
+    expand_header_name(a).eq_ignore_ascii_case(expand_header_name(b))
+}
+
+/// Splits on commas outside quotes and angle brackets.
+fn split_values(value: &str) -> Vec<&str> {
+    let mut values = Vec::new();
+    let (mut quoted, mut bracketed, mut start) = (false, false, 0);
+    let mut chars = value.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if quoted => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            '<' if !quoted => bracketed = true,
+            '>' if !quoted => bracketed = false,
+            ',' if !quoted && !bracketed => {
+                values.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    values.push(value[start..].trim());
+    values.retain(|v| !v.is_empty());
+    values
+}
+
+/// SIP headers in the order they were added. Names compare case-insensitively and compact
+/// forms match their full names.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SipHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl SipHeaders {
+    pub fn new() -> Self {
+        SipHeaders::default()
+    }
+
+    /// Parses CRLF (or LF) separated headers, unfolding continuation lines.
+    pub fn parse(s: &str) -> Self {
+        let mut headers = SipHeaders::new();
+        for line in s.lines() {
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = headers.headers.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+                continue;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.add(name.trim(), value.trim());
+            }
+        }
+        headers
+    }
+
+    /// Appends a header, keeping any existing ones with the same name.
+    pub fn add(&mut self, name: &str, value: impl Into<String>) -> &mut Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    /// Replaces every header called `name` with one, at the position of the first.
+    pub fn set(&mut self, name: &str, value: impl Into<String>) -> &mut Self {
+        match self.headers.iter().position(|(n, _)| same_header(n, name)) {
+            Some(i) => {
+                self.headers[i].1 = value.into();
+                let mut index = 0;
+                self.headers.retain(|(n, _)| {
+                    index += 1;
+                    index - 1 == i || !same_header(n, name)
+                });
+            }
+            None => {
+                self.add(name, value);
+            }
+        }
+        self
+    }
+
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        self.headers.retain(|(n, _)| !same_header(n, name));
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// The first header called `name`, as written.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| same_header(n, name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of `name`, splitting comma separated lists for headers that allow them.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        let list = !SINGLE_VALUED.contains(&expand_header_name(name).to_ascii_lowercase().as_str());
+        self.headers.iter()
+            .filter(|(n, _)| same_header(n, name))
+            .flat_map(|(_, v)| if list { split_values(v) } else { vec![v.as_str()] })
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// One `Name: value` string per header, for the `extra_headers` of `ua_session_*`.
+    pub fn to_extra_headers(&self) -> Vec<String> {
+        self.iter().map(|(n, v)| format!("{}: {}", n, v)).collect()
+    }
+
+    fn name_addrs(&self, name: &str) -> Vec<NameAddr> {
+        self.get_all(name).into_iter().filter_map(NameAddr::parse).collect()
+    }
+
+    /// Contact bindings; a `*` wildcard is left out.
+    pub fn contact(&self) -> Vec<NameAddr> {
+        self.get_all("Contact").into_iter()
+            .filter(|v| *v != "*")
+            .filter_map(NameAddr::parse)
+            .collect()
+    }
+
+    pub fn p_asserted_identity(&self) -> Vec<NameAddr> {
+        self.name_addrs("P-Asserted-Identity")
+    }
+
+    /// Diversion entries, most recent first, with `reason`/`counter` among the params.
+    pub fn diversion(&self) -> Vec<NameAddr> {
+        self.name_addrs("Diversion")
+    }
+
+    pub fn replaces(&self) -> Option<Replaces> {
+        self.get("Replaces")?.parse().ok()
+    }
+
+    /// Sequence number and method.
+    pub fn cseq(&self) -> Option<(u32, &str)> {
+        let (number, method) = self.get("CSeq")?.split_once(' ')?;
+        Some((number.trim().parse().ok()?, method.trim()))
+    }
+
+    /// Retry-After in seconds, ignoring any comment or parameters.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let value = self.get("Retry-After")?;
+        let seconds = value.split(|c: char| !c.is_ascii_digit()).next()?;
+        seconds.parse().ok().map(Duration::from_secs)
+    }
+}
+
+/// The CRLF terminated form `t_uac_dlg` takes for `headers`.
+impl fmt::Display for SipHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in &self.headers {
+            write!(f, "{}: {}\r\n", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<N: AsRef<str>, V: Into<String>> FromIterator<(N, V)> for SipHeaders {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> Self {
+        let mut headers = SipHeaders::new();
+        for (name, value) in iter {
+            headers.add(name.as_ref(), value);
+        }
+        headers
+    }
+}
+
+/// The Replaces header of RFC 3891.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Replaces {
+    pub call_id: String,
+    pub to_tag: String,
+    pub from_tag: String,
+    pub early_only: bool,
+}
+
+impl FromStr for Replaces {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut parts = s.split(';').map(str::trim);
+        let call_id = parts.next().filter(|c| !c.is_empty()).ok_or("missing call-id")?;
+        let mut replaces = Replaces { call_id: call_id.to_string(), to_tag: String::new(), from_tag: String::new(), early_only: false };
+        for part in parts {
+            match part.split_once('=') {
+                Some((k, v)) if k.trim().eq_ignore_ascii_case("to-tag") => replaces.to_tag = v.trim().to_string(),
+                Some((k, v)) if k.trim().eq_ignore_ascii_case("from-tag") => replaces.from_tag = v.trim().to_string(),
+                None if part.eq_ignore_ascii_case("early-only") => replaces.early_only = true,
+                _ => {}
+            }
+        }
+        if replaces.to_tag.is_empty() || replaces.from_tag.is_empty() {
+            return Err("to-tag and from-tag are required".to_string());
+        }
+        Ok(replaces)
+    }
+}
+
+impl fmt::Display for Replaces {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{};to-tag={};from-tag={}", self.call_id, self.to_tag, self.from_tag)?;
+        if self.early_only {
+            write!(f, ";early-only")?;
+        }
+        Ok(())
+    }
+}
+
+/// First line of a SIP message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StartLine {
+    /// `uri` is empty when the source doesn't carry the Request-URI.
+    Request { method: String, uri: String, version: String },
+    Response { version: String, code: u16, reason: String },
+}
+
+impl FromStr for StartLine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim_end();
+        if s.starts_with("SIP/") {
+            let mut parts = s.splitn(3, ' ');
+            let version = parts.next().unwrap_or_default().to_string();
+            let code = parts.next().and_then(|c| c.parse().ok()).ok_or_else(|| format!("bad status line {:?}", s))?;
+            return Ok(StartLine::Response { version, code, reason: parts.next().unwrap_or("").to_string() });
+        }
+        let parts: Vec<&str> = s.split(' ').collect();
+        // a header such as `Via: SIP/2.0/UDP ...` is not a request line
+        let [method, uri, version] = parts[..] else {
+            return Err(format!("bad request line {:?}", s));
+        };
+        if method.contains(':') || !version.starts_with("SIP/") {
+            return Err(format!("bad request line {:?}", s));
+        }
+        Ok(StartLine::Request { method: method.to_string(), uri: uri.to_string(), version: version.to_string() })
+    }
+}
+
+impl fmt::Display for StartLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartLine::Request { method, uri, version } => write!(f, "{} {} {}", method, uri, version),
+            StartLine::Response { version, code, reason } => write!(f, "{} {} {}", version, code, reason),
+        }
+    }
+}
+
+/// A SIP request or response. Prints with CRLF line endings whatever it was parsed from.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SipMessage {
+    pub start_line: StartLine,
+    pub headers: SipHeaders,
+    pub body: String,
+}
+
+impl SipMessage {
+    /// Parses headers and body that come without a start line, as mi_tm prints replies.
+    pub fn from_parts(start_line: StartLine, s: &str) -> Self {
+        let (headers, body) = split_body(s);
+        SipMessage { start_line, headers: SipHeaders::parse(headers), body: body.to_string() }
+    }
+
+    pub fn method(&self) -> Option<&str> {
+        match &self.start_line {
+            StartLine::Request { method, .. } => Some(method),
+            StartLine::Response { .. } => self.cseq().map(|(_, method)| method),
+        }
+    }
+
+    pub fn status_code(&self) -> Option<u16> {
+        match &self.start_line {
+            StartLine::Response { code, .. } => Some(*code),
+            StartLine::Request { .. } => None,
+        }
+    }
+
+    pub fn call_id(&self) -> Option<&str> {
+        self.headers.get("Call-ID")
+    }
+
+    pub fn cseq(&self) -> Option<(u32, &str)> {
+        self.headers.cseq()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.headers.retry_after()
+    }
+}
+
+fn split_body(s: &str) -> (&str, &str) {
+    s.split_once("\r\n\r\n")
+        .or_else(|| s.split_once("\n\n"))
+        .unwrap_or((s, ""))
+}
+
+impl FromStr for SipMessage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (head, body) = split_body(s);
+        let (start_line, headers) = head.split_once('\n').unwrap_or((head, ""));
+        Ok(SipMessage {
+            start_line: start_line.parse()?,
+            headers: SipHeaders::parse(headers),
+            body: body.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for SipMessage {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl From<SipMessage> for String {
+    fn from(message: SipMessage) -> String {
+        message.to_string()
+    }
+}
+
+impl fmt::Display for SipMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\r\n{}\r\n{}", self.start_line, self.headers, self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers() {
+        let mut headers = SipHeaders::parse(concat!(
+            "f: \"Smith, John\" <sip:john@example.com>;tag=1\r\n",
+            "m: <sip:a@10.0.0.1>;q=0.5, <sip:b@10.0.0.2>\r\n",
+            "Contact: sip:c@10.0.0.3;expires=60\r\n",
+            "Diversion: <sip:100@example.com>;reason=unconditional;counter=1,\r\n",
+            " <sip:200@example.com>;reason=user-busy\r\n",
+            "Replaces: 425928@bobster.example.org;to-tag=7743;from-tag=6472;early-only\r\n",
+        ));
+        assert_eq!(headers.len(), 5);
+        assert_eq!(NameAddr::parse(headers.get("FROM").unwrap()).unwrap().display_name.as_deref(), Some("Smith, John"));
+        let contacts = headers.contact();
+        assert_eq!(contacts.iter().map(|c| c.uri.as_str()).collect::<Vec<_>>(), vec!["sip:a@10.0.0.1", "sip:b@10.0.0.2", "sip:c@10.0.0.3"]);
+        assert_eq!((contacts[0].param("q"), contacts[2].param("expires")), (Some("0.5"), Some("60")));
+        let diversion = headers.diversion();
+        assert_eq!(diversion[1].param("reason"), Some("user-busy"));
+        let replaces = headers.replaces().unwrap();
+        assert!(replaces.early_only);
+        assert_eq!(replaces.to_string(), headers.get("replaces").unwrap());
+
+        headers.set("Contact", "<sip:d@10.0.0.4>").remove("Diversion").add("P-Asserted-Identity", "<sip:+15551234@example.com>");
+        assert_eq!(headers.to_extra_headers(), vec![
+            "f: \"Smith, John\" <sip:john@example.com>;tag=1",
+            "m: <sip:d@10.0.0.4>",
+            "Replaces: 425928@bobster.example.org;to-tag=7743;from-tag=6472;early-only",
+            "P-Asserted-Identity: <sip:+15551234@example.com>",
+        ]);
+        assert_eq!(headers.p_asserted_identity()[0].uri, "sip:+15551234@example.com");
+        assert_eq!(SipHeaders::parse(&headers.to_string()), headers);
+    }
+
+    #[test]
+    fn test_message() {
+        let text = "SIP/2.0 503 Service Unavailable\r\nVia: SIP/2.0/UDP 10.0.0.1;branch=z9hG4bK1\r\nCSeq: 1 INVITE\r\nRetry-After: 120 (maintenance);duration=3600\r\nContact: <sip:backup@10.0.0.7>\r\nContent-Length: 0\r\n\r\n";
+        let message: SipMessage = text.parse().unwrap();
+        assert_eq!(message.status_code(), Some(503));
+        assert_eq!(message.method(), Some("INVITE"));
+        assert_eq!(message.retry_after(), Some(Duration::from_secs(120)));
+        assert_eq!(message.headers.contact()[0].uri, "sip:backup@10.0.0.7");
+        assert_eq!(message.to_string(), text);
+
+        let request: SipMessage = "BYE sip:alice@10.0.0.1 SIP/2.0\nCSeq: 3 BYE\n\n".parse().unwrap();
+        assert_eq!(request.start_line, StartLine::Request { method: "BYE".into(), uri: "sip:alice@10.0.0.1".into(), version: "SIP/2.0".into() });
+        assert_eq!(request.cseq(), Some((3, "BYE")));
+        assert!("garbage".parse::<SipMessage>().is_err());
+        assert!("Via: SIP/2.0/UDP 10.0.0.1;branch=z9hG4bK1\r\n\r\n".parse::<SipMessage>().is_err());
+    }
+}
@@ -0,0 +1,470 @@
+//! On-disk spool of notifications, so handlers that restart pick up where they left off.
+//!
+//! Notifications are appended as JSON lines to segment files named after the offset of their
+//! first record. Each consumer keeps its own committed offset in `<name>.offset`; everything
+//! from there on is delivered again after a restart until it is acknowledged.
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+use super::*;
+
+#[derive(Clone, Debug)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    /// A new segment is started once the current one reaches this size.
+    pub segment_bytes: u64,
+    /// Oldest segments are deleted, acknowledged or not, to stay under this size.
+    pub max_bytes: Option<u64>,
+    /// Segments whose newest record is older than this are deleted.
+    pub max_age: Option<Duration>,
+}
+
+impl SpoolConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        SpoolConfig {
+            dir: dir.into(),
+            segment_bytes: 16 * 1024 * 1024,
+            max_bytes: None,
+            max_age: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpoolEntry {
+    pub offset: u64,
+    /// milliseconds since the Unix epoch
+    pub time: u64,
+    pub notification: Notification,
+}
+
+struct Segment {
+    base: u64,
+    path: PathBuf,
+    bytes: u64,
+    last_time: u64,
+}
+
+struct Writer {
+    segments: Vec<Segment>,
+    file: Option<File>,
+    next_offset: u64,
+}
+
+struct Shared {
+    config: SpoolConfig,
+    writer: Mutex<Writer>,
+    appended: Notify,
+}
+
+/// Handle to a spool directory; clones share it.
+#[derive(Clone)]
+pub struct Spool {
+    shared: Arc<Shared>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+fn segment_path(dir: &Path, base: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", base))
+}
+
+// the last entry that parses, and the length up to the end of the last complete line
+fn last_entry(path: &Path) -> io::Result<(Option<SpoolEntry>, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut last, mut complete) = (None, 0);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let size = reader.read_until(b'\n', &mut line)?;
+        if size == 0 || !line.ends_with(b"\n") {
+            return Ok((last, complete));
+        }
+        complete += size as u64;
+        if let Ok(entry) = serde_json::from_slice(&line) {
+            last = Some(entry);
+        }
+    }
+}
+
+// never deletes the segment being written
+fn enforce_retention(config: &SpoolConfig, writer: &mut Writer) {
+    let cutoff = config.max_age.map(|age| now_ms().saturating_sub(age.as_millis() as u64));
+    while writer.segments.len() > 1 {
+        let total: u64 = writer.segments.iter().map(|s| s.bytes).sum();
+        let oldest = &writer.segments[0];
+        let too_big = config.max_bytes.is_some_and(|max| total > max);
+        let too_old = cutoff.is_some_and(|cutoff| oldest.last_time < cutoff);
+        if !too_big && !too_old {
+            break;
+        }
+        warn!("spool retention removing segment {:?}", oldest.path);
+        if let Err(e) = fs::remove_file(&oldest.path) {
+            warn!("failed to remove {:?}: {:?}", oldest.path, e);
+        }
+        writer.segments.remove(0);
+    }
+}
+
+impl Spool {
+    pub fn open(config: SpoolConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let mut segments = Vec::new();
+        for dir_entry in fs::read_dir(&config.dir)? {
+            let path = dir_entry?.path();
+            let base = path.file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".log"))
+                .and_then(|n| n.parse().ok());
+            if let Some(base) = base {
+                let bytes = fs::metadata(&path)?.len();
+                segments.push(Segment { base, path, bytes, last_time: 0 });
+            }
+        }
+        segments.sort_by_key(|s| s.base);
+
+        let mut next_offset = 0;
+        for segment in &mut segments {
+            let (last, complete) = last_entry(&segment.path)?;
+            if complete < segment.bytes {
+                // a crash midway through an append; new records must start on a line of their own
+                warn!("truncating partial record at the end of {:?}", segment.path);
+                File::options().write(true).open(&segment.path)?.set_len(complete)?;
+                segment.bytes = complete;
+            }
+            if let Some(last) = last {
+                segment.last_time = last.time;
+                next_offset = last.offset + 1;
+            }
+        }
+        let file = match segments.last() {
+            Some(s) => Some(File::options().append(true).open(&s.path)?),
+            None => None,
+        };
+        debug!("opened spool {:?} at offset {}", config.dir, next_offset);
+
+        let mut writer = Writer { segments, file, next_offset };
+        enforce_retention(&config, &mut writer);
+        Ok(Spool {
+            shared: Arc::new(Shared {
+                config,
+                writer: Mutex::new(writer),
+                appended: Notify::new(),
+            }),
+        })
+    }
+
+    pub fn append(&self, notification: &Notification) -> io::Result<u64> {
+        let config = &self.shared.config;
+        let mut writer = self.shared.writer.lock().unwrap();
+        let entry = SpoolEntry { offset: writer.next_offset, time: now_ms(), notification: notification.clone() };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let full = writer.segments.last().is_none_or(|s| s.bytes >= config.segment_bytes);
+        if full || writer.file.is_none() {
+            let path = segment_path(&config.dir, entry.offset);
+            writer.file = Some(File::options().create(true).append(true).open(&path)?);
+            writer.segments.push(Segment { base: entry.offset, path, bytes: 0, last_time: entry.time });
+        }
+        let file = writer.file.as_mut().expect("segment file is open");
+        file.write_all(&line)?;
+        file.sync_data()?;
+        let segment = writer.segments.last_mut().expect("segment exists");
+        segment.bytes += line.len() as u64;
+        segment.last_time = entry.time;
+        writer.next_offset += 1;
+        enforce_retention(config, &mut writer);
+        drop(writer);
+
+        self.shared.appended.notify_waiters();
+        Ok(entry.offset)
+    }
+
+    /// Appends everything the receiver decodes, until its socket fails.
+    pub async fn fill_from(&self, receiver: UdpNotificationReceiver) -> io::Result<()> {
+        receiver.run(|n| {
+            if let Err(e) = self.append(&n) {
+                warn!("failed to spool {}: {:?}", n.event_name(), e);
+            }
+            async {}
+        }).await
+    }
+
+    /// A named consumer, resuming from its last committed offset.
+    pub fn consumer(&self, name: &str) -> io::Result<SpoolConsumer> {
+        let offset_path = self.shared.config.dir.join(format!("{}.offset", name));
+        let committed = match fs::read_to_string(&offset_path) {
+            Ok(s) => s.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let mut consumer = SpoolConsumer {
+            spool: self.clone(),
+            offset_path,
+            committed,
+            acked: BTreeSet::new(),
+            position: committed,
+            reader: None,
+        };
+        if consumer.skip_removed() {
+            consumer.persist()?;
+        }
+        Ok(consumer)
+    }
+
+    /// Offset of the oldest entry retention has kept.
+    fn first_offset(&self) -> u64 {
+        let writer = self.shared.writer.lock().unwrap();
+        writer.segments.first().map_or(writer.next_offset, |s| s.base)
+    }
+
+    fn segment_for(&self, offset: u64) -> Option<(u64, PathBuf)> {
+        let writer = self.shared.writer.lock().unwrap();
+        writer.segments.iter()
+            .rev()
+            .find(|s| s.base <= offset)
+            .or_else(|| writer.segments.first())
+            .map(|s| (s.base, s.path.clone()))
+    }
+
+    fn next_segment(&self, base: u64) -> Option<(u64, PathBuf)> {
+        let writer = self.shared.writer.lock().unwrap();
+        writer.segments.iter()
+            .find(|s| s.base > base)
+            .map(|s| (s.base, s.path.clone()))
+    }
+}
+
+pub struct SpoolConsumer {
+    spool: Spool,
+    offset_path: PathBuf,
+    // everything below this has been acknowledged and persisted
+    committed: u64,
+    acked: BTreeSet<u64>,
+    position: u64,
+    reader: Option<(u64, BufReader<File>)>,
+}
+
+impl SpoolConsumer {
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    /// The next entry, waiting for one to be appended if needed.
+    pub async fn next(&mut self) -> io::Result<SpoolEntry> {
+        loop {
+            let shared = self.spool.shared.clone();
+            let appended = shared.appended.notified();
+            tokio::pin!(appended);
+            appended.as_mut().enable();
+
+            if let Some(entry) = self.read_next()? {
+                self.position = entry.offset + 1;
+                return Ok(entry);
+            }
+            appended.await;
+        }
+    }
+
+    fn read_next(&mut self) -> io::Result<Option<SpoolEntry>> {
+        loop {
+            if self.reader.is_none() {
+                let Some((base, path)) = self.spool.segment_for(self.position) else {
+                    return Ok(None);
+                };
+                match File::open(&path) {
+                    Ok(file) => self.reader = Some((base, BufReader::new(file))),
+                    // removed by retention in the meantime
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            let (base, reader) = self.reader.as_mut().expect("reader is open");
+            let mut line = String::new();
+            let size = reader.read_line(&mut line)?;
+            if size > 0 && !line.ends_with('\n') {
+                // the writer is midway through this line
+                reader.seek(SeekFrom::Current(-(size as i64)))?;
+                return Ok(None);
+            }
+            if size == 0 {
+                match self.spool.next_segment(*base) {
+                    Some((base, path)) => self.reader = Some((base, BufReader::new(File::open(path)?))),
+                    None => return Ok(None),
+                }
+                continue;
+            }
+            let entry: SpoolEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("skipping corrupt spool record: {:?}", e);
+                    continue;
+                }
+            };
+            if entry.offset >= self.position {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    /// Marks one entry as handled. The committed offset only moves past an entry once it and
+    /// everything before it is acknowledged.
+    pub fn ack(&mut self, offset: u64) -> io::Result<()> {
+        let mut moved = self.skip_removed();
+        if offset >= self.committed {
+            self.acked.insert(offset);
+        }
+        while self.acked.remove(&self.committed) {
+            self.committed += 1;
+            moved = true;
+        }
+        if moved {
+            self.persist()?;
+        }
+        Ok(())
+    }
+
+    // entries deleted by retention can never be acknowledged, so stop waiting for them
+    fn skip_removed(&mut self) -> bool {
+        let first = self.spool.first_offset();
+        if self.committed >= first {
+            return false;
+        }
+        warn!("spool retention dropped unacknowledged entries {}..{}", self.committed, first);
+        self.committed = first;
+        self.acked = self.acked.split_off(&first);
+        self.position = self.position.max(first);
+        true
+    }
+
+    fn persist(&self) -> io::Result<()> {
+        let tmp = self.offset_path.with_extension("offset.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.committed.to_string().as_bytes())?;
+        file.sync_data()?;
+        fs::rename(&tmp, &self.offset_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(node_id: usize) -> Notification {
+        Notification::EClustererNodeStateChange(ClustererNodeStateChange { cluster_id: 1, node_id, new_state: ClusterNodeState::Up })
+    }
+
+    fn node_id(entry: &SpoolEntry) -> usize {
+        match &entry.notification {
+            Notification::EClustererNodeStateChange(c) => c.node_id,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redelivery_and_retention() {
+        let dir = std::env::temp_dir().join(format!("opensips-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = SpoolConfig { segment_bytes: 1, max_bytes: Some(600), ..SpoolConfig::new(&dir) };
+
+        let spool = Spool::open(config.clone()).unwrap();
+        for i in 0..3 {
+            assert_eq!(spool.append(&change(i)).unwrap(), i as u64);
+        }
+        let mut consumer = spool.consumer("billing").unwrap();
+        let first = consumer.next().await.unwrap();
+        let second = consumer.next().await.unwrap();
+        assert_eq!((node_id(&first), node_id(&second)), (0, 1));
+        consumer.ack(second.offset).unwrap();
+        assert_eq!(consumer.committed(), 0);
+        consumer.ack(first.offset).unwrap();
+        assert_eq!(consumer.committed(), 2);
+        drop((consumer, spool));
+
+        // after a restart the unacknowledged entry comes back, and new ones follow it
+        let spool = Spool::open(config).unwrap();
+        let mut consumer = spool.consumer("billing").unwrap();
+        assert_eq!(node_id(&consumer.next().await.unwrap()), 2);
+        let waiting = tokio::spawn(async move { consumer.next().await.unwrap() });
+        tokio::task::yield_now().await;
+        spool.append(&change(3)).unwrap();
+        assert_eq!(waiting.await.unwrap().offset, 3);
+
+        // one record per segment, and only a few fit in 600 bytes
+        for i in 4..10 {
+            spool.append(&change(i)).unwrap();
+        }
+        let segments = fs::read_dir(&dir).unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|x| x == "log"))
+            .count();
+        assert!(segments < 10);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_retention_drops_unacked() {
+        let dir = std::env::temp_dir().join(format!("opensips-spool-dropped-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = SpoolConfig { segment_bytes: 1, max_age: Some(Duration::from_millis(10)), ..SpoolConfig::new(&dir) };
+
+        let spool = Spool::open(config.clone()).unwrap();
+        for i in 0..3 {
+            spool.append(&change(i)).unwrap();
+        }
+        let mut slow = spool.consumer("slow").unwrap();
+        assert_eq!(slow.next().await.unwrap().offset, 0);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        spool.append(&change(3)).unwrap();
+        assert_eq!(spool.first_offset(), 3);
+
+        // entries 0 to 2 are gone, so acknowledging 3 commits everything up to it
+        slow.ack(3).unwrap();
+        assert_eq!(slow.committed(), 4);
+        assert!(slow.acked.is_empty());
+        assert_eq!(fs::read_to_string(dir.join("slow.offset")).unwrap(), "4");
+        for i in 4..6 {
+            spool.append(&change(i)).unwrap();
+        }
+        drop((slow, spool));
+
+        // a quiet spool still expires old segments when it is opened, keeping the active one
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let spool = Spool::open(config).unwrap();
+        assert_eq!(spool.first_offset(), 5);
+        assert_eq!(spool.consumer("slow").unwrap().committed(), 5);
+        let _ = fs::remove_dir_all(&dir);
+    }
+    #[tokio::test]
+    async fn test_partial_record_after_crash() {
+        let dir = std::env::temp_dir().join(format!("opensips-spool-partial-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = SpoolConfig::new(&dir);
+
+        let spool = Spool::open(config.clone()).unwrap();
+        spool.append(&change(0)).unwrap();
+        drop(spool);
+        let segment = segment_path(&dir, 0);
+        File::options().append(true).open(&segment).unwrap().write_all(b"{\"offset\":1,\"ti").unwrap();
+
+        let spool = Spool::open(config).unwrap();
+        assert_eq!(spool.append(&change(1)).unwrap(), 1);
+        let mut consumer = spool.consumer("billing").unwrap();
+        for i in 0..2 {
+            let entry = consumer.next().await.unwrap();
+            assert_eq!((entry.offset, node_id(&entry)), (i, i as usize));
+            consumer.ack(entry.offset).unwrap();
+        }
+        assert_eq!(consumer.committed(), 2);
+        assert_eq!(fs::read_to_string(&segment).unwrap().lines().count(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use futures::stream::{self, Stream};
+use tokio::sync::Notify;
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, warn};
+use super::*;
+use super::metrics::ReceiverMetrics;
+
+/// A decoded notification along with where and when it arrived.
+#[derive(Debug)]
+pub struct Received {
+    pub notification: Notification,
+    pub source: SocketAddr,
+    pub received_at: SystemTime,
+}
+
+/// What happens to new datagrams while the stream's buffer is full.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop reading the socket until there is room; the kernel buffer absorbs (or drops) the rest.
+    #[default]
+    Backpressure,
+    DropNewest,
+    DropOldest,
+}
+
+type Item = Result<Received, DecodeError>;
+
+struct Queue {
+    items: Mutex<VecDeque<Item>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    readable: Notify,
+    writable: Notify,
+    closed: AtomicBool,
+    dropped: AtomicU64,
+    metrics: Arc<ReceiverMetrics>,
+}
+
+impl Queue {
+    async fn push(&self, item: Item) {
+        loop {
+            {
+                let mut items = self.items.lock().unwrap();
+                if items.len() < self.capacity {
+                    items.push_back(item);
+                    self.metrics.record_queue_depth(items.len());
+                    break;
+                }
+                match self.policy {
+                    OverflowPolicy::Backpressure => {}
+                    OverflowPolicy::DropNewest => {
+                        self.record_dropped();
+                        return;
+                    }
+                    OverflowPolicy::DropOldest => {
+                        items.pop_front();
+                        items.push_back(item);
+                        self.record_dropped();
+                        break;
+                    }
+                }
+            }
+            self.writable.notified().await;
+        }
+        self.readable.notify_one();
+    }
+
+    fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        self.metrics.record_dropped();
+    }
+
+    async fn pop(&self) -> Option<Item> {
+        loop {
+            let item = {
+                let mut items = self.items.lock().unwrap();
+                let item = items.pop_front();
+                self.metrics.record_queue_depth(items.len());
+                item
+            };
+            if let Some(item) = item {
+                self.writable.notify_one();
+                return Some(item);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.readable.notify_one();
+    }
+}
+
+/// Notifications read from a UDP socket by a background task.
+///
+/// Cancelling the token stops reading; whatever is already buffered is still delivered before
+/// the stream ends, as it does when the socket fails. Dropping the stream stops the task.
+pub struct NotificationStream {
+    inner: Pin<Box<dyn Stream<Item = Item> + Send>>,
+    queue: Arc<Queue>,
+    _stop: DropGuard,
+}
+
+impl NotificationStream {
+    /// Notifications thrown away because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.queue.items.lock().unwrap().len()
+    }
+}
+
+impl Stream for NotificationStream {
+    type Item = Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+impl UdpNotificationReceiver {
+    pub fn into_stream(self, buffer: usize, policy: OverflowPolicy, shutdown: CancellationToken) -> NotificationStream {
+        let queue = Arc::new(Queue {
+            items: Mutex::new(VecDeque::with_capacity(buffer)),
+            capacity: buffer.max(1),
+            policy,
+            readable: Notify::new(),
+            writable: Notify::new(),
+            closed: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+            metrics: self.metrics.clone(),
+        });
+        let stop = shutdown.child_token();
+        tokio::spawn(read_socket(self, queue.clone(), stop.clone()));
+
+        let inner = stream::unfold(queue.clone(), |queue| async move {
+            queue.pop().await.map(|item| (item, queue))
+        });
+        NotificationStream { inner: Box::pin(inner), queue, _stop: stop.drop_guard() }
+    }
+}
+
+// errors about an earlier datagram or a signal, after which the socket still works; UDP
+// sockets report ICMP errors for sent packets on a later receive
+fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(e.kind(), Interrupted | WouldBlock | ConnectionRefused | ConnectionReset | TimedOut)
+}
+
+async fn read_socket(receiver: UdpNotificationReceiver, queue: Arc<Queue>, stop: CancellationToken) {
+    let UdpNotificationReceiver { socket, allowed_sources, metrics, recorder, .. } = receiver;
+    let mut buf = vec![0u8; 65536];
+    debug!("starting UDP stream loop");
+    'receive: loop {
+        let (size, source) = tokio::select! {
+            _ = stop.cancelled() => break,
+            result = socket.recv_from(&mut buf) => match result {
+                Ok(r) => r,
+                Err(e) if is_transient(&e) => {
+                    debug!("error receiving: {:?}", e);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("UDP stream stopping on receive error: {:?}", e);
+                    break;
+                }
+            },
+        };
+        metrics.record_datagram();
+        if !UdpNotificationReceiver::accepts(&allowed_sources, &source) {
+            debug!("ignoring packet from {:?}", source);
+            metrics.record_rejected();
+            continue;
+        }
+        if let Some(recorder) = &recorder {
+            if let Err(e) = recorder.record_payload(Some(source), &buf[..size]).await {
+                debug!("failed to record datagram: {:?}", e);
+            }
+        }
+        let received_at = SystemTime::now();
+        let items: Vec<Item> = decode_payload(&buf[..size]).into_iter()
+            .map(|result| match result {
+                Ok(notification) => {
+                    metrics.record_notification(notification.event_name());
+                    Ok(Received { notification, source, received_at })
+                }
+                Err(PayloadError { payload, error }) => {
+                    metrics.record_decode_error(&payload);
+                    Err(DecodeError { source, received_at, payload, error })
+                }
+            })
+            .collect();
+        for item in items {
+            tokio::select! {
+                _ = stop.cancelled() => break 'receive,
+                _ = queue.push(item) => {}
+            }
+        }
+    }
+    debug!("UDP stream loop stopped");
+    queue.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_drop_oldest_and_shutdown() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let receiver = UdpNotificationReceiver::new(socket);
+        let metrics = receiver.metrics.clone();
+        let stream = receiver.into_stream(2, OverflowPolicy::DropOldest, shutdown.clone());
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for node_id in 0..4 {
+            let payload = format!(r#"{{"jsonrpc":"2.0","method":"E_CLUSTERER_NODE_STATE_CHANGE","params":{{"cluster_id":1,"node_id":{},"new_state":1}}}}"#, node_id);
+            sender.send_to(payload.as_bytes(), addr).await.unwrap();
+        }
+        sender.send_to(b"garbage", addr).await.unwrap();
+        while stream.dropped() < 3 {
+            tokio::task::yield_now().await;
+        }
+        shutdown.cancel();
+
+        let items: Vec<_> = stream.collect().await;
+        assert_eq!(items.len(), 2);
+        assert!(matches!(items[0], Ok(Received { notification: Notification::EClustererNodeStateChange(ClustererNodeStateChange { node_id: 3, .. }), .. })));
+        assert_eq!(items[1].as_ref().unwrap_err().payload, b"garbage");
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.datagrams_received, 5);
+        assert_eq!(stats.notifications["E_CLUSTERER_NODE_STATE_CHANGE"], 4);
+        assert_eq!(stats.decode_errors["<invalid>"], 1);
+        assert_eq!((stats.dropped, stats.queue_depth, stats.max_queue_depth), (3, 0, 2));
+
+        assert!(is_transient(&std::io::ErrorKind::ConnectionRefused.into()));
+        assert!(!is_transient(&std::io::ErrorKind::Other.into()));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use futures::future::join_all;
+use tokio::sync::mpsc;
+use tokio::time::interval;
+use tracing::{debug, warn};
+use super::*;
+use super::clusterer::{Cluster, SharedTagStatus, TagState};
+
+/// What one OpenSIPS node reported on the last poll.
+#[derive(Clone, Debug, Default)]
+pub struct NodeView {
+    pub clusters: Vec<Cluster>,
+    pub shtags: Vec<SharedTagStatus>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Topology {
+    /// keyed by the name given to the watcher, not the clusterer node id
+    pub nodes: BTreeMap<String, NodeView>,
+    /// last state seen in node state change events, keyed by (cluster_id, node_id)
+    pub node_states: BTreeMap<(usize, usize), ClusterNodeState>,
+    /// which node a (cluster_id, node_id) is, learned from the polled cluster lists or given
+    /// to the watcher
+    pub node_ids: BTreeMap<(usize, usize), String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShtagConflict {
+    MultipleActive { cluster_id: usize, tag: String, nodes: Vec<String> },
+    NoActive { cluster_id: usize, tag: String },
+}
+
+impl Topology {
+    /// `clusterer_list` shows every node of a cluster but the one answering, so a node's own
+    /// id is the one the others list and it doesn't. Needs at least two reachable nodes per
+    /// cluster; ids already known are kept.
+    pub fn learn_node_ids(&mut self) {
+        let mut listed: BTreeMap<usize, Vec<(&String, BTreeSet<usize>)>> = BTreeMap::new();
+        for (name, view) in self.nodes.iter().filter(|(_, v)| v.error.is_none()) {
+            for cluster in &view.clusters {
+                let ids = cluster.nodes.iter().filter_map(|n| usize::try_from(n.node_id).ok()).collect();
+                listed.entry(cluster.cluster_id).or_default().push((name, ids));
+            }
+        }
+        for (cluster_id, views) in listed {
+            let all: BTreeSet<usize> = views.iter().flat_map(|(_, ids)| ids.iter().copied()).collect();
+            for (name, ids) in &views {
+                let own: Vec<&usize> = all.difference(ids).collect();
+                if let [node_id] = own[..] {
+                    self.node_ids.entry((cluster_id, *node_id)).or_insert_with(|| name.to_string());
+                }
+            }
+        }
+    }
+
+    /// Whether the rest of `cluster_id` last reported `node` down.
+    pub fn is_down(&self, cluster_id: usize, node: &str) -> bool {
+        self.node_ids.iter()
+            .filter(|((cluster, _), name)| *cluster == cluster_id && *name == node)
+            .any(|(id, _)| self.node_states.get(id) == Some(&ClusterNodeState::Down))
+    }
+
+    /// Finds sharing tags that are active on more than one node, or on none of the reachable
+    /// nodes the cluster still sees as up.
+    pub fn shtag_conflicts(&self) -> Vec<ShtagConflict> {
+        let mut tags: BTreeMap<(usize, String), Vec<String>> = BTreeMap::new();
+        for (name, view) in self.nodes.iter().filter(|(_, v)| v.error.is_none()) {
+            for shtag in &view.shtags {
+                let active = tags.entry((shtag.cluster, shtag.tag.clone())).or_default();
+                if shtag.state == TagState::Active {
+                    active.push(name.clone());
+                }
+            }
+        }
+        tags.into_iter()
+            .filter_map(|((cluster_id, tag), nodes)| match nodes.len() {
+                0 => Some(ShtagConflict::NoActive { cluster_id, tag }),
+                // active only on a node the rest of the cluster has lost
+                1 if self.is_down(cluster_id, &nodes[0]) => Some(ShtagConflict::NoActive { cluster_id, tag }),
+                1 => None,
+                _ => Some(ShtagConflict::MultipleActive { cluster_id, tag, nodes }),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TopologyAlarm {
+    NodeUnreachable { node: String, error: String },
+    Shtag(ShtagConflict),
+    Remediated { cluster_id: usize, tag: String, node: String },
+    RemediationFailed { cluster_id: usize, tag: String, node: String, error: String },
+}
+
+/// What to do about a sharing tag conflict.
+#[derive(Clone, Debug, Default)]
+pub enum ShtagPolicy {
+    #[default]
+    ReportOnly,
+    /// Make the first node in the list that is involved (or reachable and not reported down,
+    /// when no node is active) the active one with `clusterer_shtag_set_active`.
+    Prefer(Vec<String>),
+}
+
+/// Polls the clusterer state of every node and reports sharing tag split-brains.
+pub struct TopologyWatcher<C> {
+    nodes: Vec<(String, C)>,
+    poll_interval: Duration,
+    policy: ShtagPolicy,
+    alarms: mpsc::Sender<TopologyAlarm>,
+    topology: Topology,
+}
+
+impl<C> TopologyWatcher<C>
+    where C: OpenSIPSClient + Sync
+{
+    pub fn new(nodes: Vec<(String, C)>, alarms: mpsc::Sender<TopologyAlarm>) -> Self {
+        TopologyWatcher {
+            nodes,
+            poll_interval: Duration::from_secs(10),
+            policy: ShtagPolicy::default(),
+            alarms,
+            topology: Topology::default(),
+        }
+    }
+
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Tells which node `node_id` of `cluster_id` is, so that node state change events can be
+    /// applied to it. Only needed where polling can't tell, see [`Topology::learn_node_ids`].
+    pub fn node_id(mut self, name: &str, cluster_id: usize, node_id: usize) -> Self {
+        self.topology.node_ids.insert((cluster_id, node_id), name.to_string());
+        self
+    }
+
+    pub fn policy(mut self, policy: ShtagPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn topology(&self) -> &Topology {
+        &self.topology
+    }
+
+    pub fn handle_event(&mut self, change: &ClustererNodeStateChange) {
+        self.topology.node_states.insert((change.cluster_id, change.node_id), change.new_state);
+    }
+
+    /// Refreshes every node, then reports and (depending on the policy) fixes conflicts.
+    pub async fn poll(&mut self) -> Vec<ShtagConflict> {
+        let views = join_all(self.nodes.iter().map(|(name, client)| async move {
+            let view = match (client.clusterer_list().await, client.clusterer_list_shtags().await) {
+                (Ok(list), Ok(shtags)) => NodeView { clusters: list.clusters, shtags, error: None },
+                (Err(e), _) | (_, Err(e)) => NodeView { error: Some(e.to_string()), ..Default::default() },
+            };
+            (name.clone(), view)
+        })).await;
+
+        for (name, view) in views {
+            if let Some(error) = &view.error {
+                warn!("topology poll of {} failed: {}", name, error);
+                self.alarm(TopologyAlarm::NodeUnreachable { node: name.clone(), error: error.clone() }).await;
+            }
+            self.topology.nodes.insert(name, view);
+        }
+        self.topology.learn_node_ids();
+
+        let conflicts = self.topology.shtag_conflicts();
+        for conflict in &conflicts {
+            self.alarm(TopologyAlarm::Shtag(conflict.clone())).await;
+            self.remediate(conflict).await;
+        }
+        conflicts
+    }
+
+    /// Polls on the configured interval, applying node state changes as they arrive.
+    pub async fn run(mut self, mut events: mpsc::Receiver<ClustererNodeStateChange>) {
+        let mut ticker = interval(self.poll_interval);
+        let mut events_open = true;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    self.poll().await;
+                }
+                change = events.recv(), if events_open => match change {
+                    Some(change) => self.handle_event(&change),
+                    None => events_open = false,
+                },
+            }
+        }
+    }
+
+    async fn alarm(&self, alarm: TopologyAlarm) {
+        debug!("topology alarm: {:?}", alarm);
+        let _ = self.alarms.send(alarm).await;
+    }
+
+    async fn remediate(&self, conflict: &ShtagConflict) {
+        let ShtagPolicy::Prefer(preferred) = &self.policy else {
+            return;
+        };
+        let (cluster_id, tag, candidates) = match conflict {
+            ShtagConflict::MultipleActive { cluster_id, tag, nodes } => (*cluster_id, tag, Some(nodes)),
+            ShtagConflict::NoActive { cluster_id, tag } => (*cluster_id, tag, None),
+        };
+        let reachable = |name: &String| self.topology.nodes.get(name).is_some_and(|v| v.error.is_none())
+            && (candidates.is_some() || !self.topology.is_down(cluster_id, name));
+        let Some(node) = preferred.iter()
+            .filter(|name| candidates.is_none_or(|c| c.contains(name)))
+            .find(|name| reachable(name)) else {
+            return;
+        };
+        let Some((_, client)) = self.nodes.iter().find(|(name, _)| name == node) else {
+            return;
+        };
+
+        let alarm = match client.clusterer_shtag_set_active(format!("{}/{}", tag, cluster_id)).await {
+            Ok(_) => TopologyAlarm::Remediated { cluster_id, tag: tag.clone(), node: node.clone() },
+            Err(e) => TopologyAlarm::RemediationFailed { cluster_id, tag: tag.clone(), node: node.clone(), error: e.to_string() },
+        };
+        self.alarm(alarm).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::mock::MockOpenSIPS;
+
+    fn view(shtags: &[(&str, TagState)]) -> NodeView {
+        NodeView {
+            shtags: shtags.iter()
+                .map(|(tag, state)| SharedTagStatus { tag: tag.to_string(), cluster: 1, state: state.clone() })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_shtag_conflicts() {
+        let mut topology = Topology::default();
+        topology.nodes.insert("a".into(), view(&[("vip", TagState::Active), ("vip2", TagState::Backup), ("ok", TagState::Active)]));
+        topology.nodes.insert("b".into(), view(&[("vip", TagState::Active), ("vip2", TagState::Backup), ("ok", TagState::Backup)]));
+        topology.nodes.insert("c".into(), NodeView { error: Some("down".into()), ..view(&[("ok", TagState::Active)]) });
+
+        assert_eq!(topology.shtag_conflicts(), vec![
+            ShtagConflict::MultipleActive { cluster_id: 1, tag: "vip".into(), nodes: vec!["a".into(), "b".into()] },
+            ShtagConflict::NoActive { cluster_id: 1, tag: "vip2".into() },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_remediation() {
+        let (a, b) = (MockOpenSIPS::start().await.unwrap(), MockOpenSIPS::start().await.unwrap());
+        for (mock, peer) in [(&a, 2), (&b, 1)] {
+            let node = json!({"node_id": peer, "db_id": peer, "url": "bin:10.0.0.1:5566", "link_state": "Up",
+                "state": "enabled", "next_hop": "", "description": ""});
+            mock.respond("clusterer_list", json!({"Clusters": [{"cluster_id": 1, "Nodes": [node]}]}));
+            mock.respond("clusterer_list_shtags", json!([{"Tag": "vip", "Cluster": 1, "State": "active"}]));
+            mock.respond("clusterer_shtag_set_active", "OK");
+        }
+        let (alarms, mut raised) = mpsc::channel(16);
+        let mut watcher = TopologyWatcher::new(vec![("a".to_string(), a.client()), ("b".to_string(), b.client())], alarms)
+            .policy(ShtagPolicy::Prefer(vec!["a".into(), "b".into()]));
+
+        let conflicts = watcher.poll().await;
+        assert_eq!(conflicts, vec![ShtagConflict::MultipleActive { cluster_id: 1, tag: "vip".into(), nodes: vec!["a".into(), "b".into()] }]);
+        a.assert_called_with("clusterer_shtag_set_active", json!({"tag": "vip/1"}));
+        assert_eq!(watcher.topology().node_ids, BTreeMap::from([((1, 1), "a".to_string()), ((1, 2), "b".to_string())]));
+        assert!(b.calls("clusterer_shtag_set_active").is_empty());
+
+        // only a, which the cluster has lost, still holds the tag, so b takes over despite the preference
+        b.respond("clusterer_list_shtags", json!([{"Tag": "vip", "Cluster": 1, "State": "backup"}]));
+        watcher.handle_event(&ClustererNodeStateChange { cluster_id: 1, node_id: 1, new_state: ClusterNodeState::Down });
+        let conflicts = watcher.poll().await;
+        assert_eq!(conflicts, vec![ShtagConflict::NoActive { cluster_id: 1, tag: "vip".into() }]);
+        b.assert_called_with("clusterer_shtag_set_active", json!({"tag": "vip/1"}));
+        assert_eq!(a.calls("clusterer_shtag_set_active").len(), 1);
+
+        raised.close();
+        let mut remediated = Vec::new();
+        while let Some(alarm) = raised.recv().await {
+            if let TopologyAlarm::Remediated { node, .. } = alarm {
+                remediated.push(node);
+            }
+        }
+        assert_eq!(remediated, vec!["a", "b"]);
+    }
+}
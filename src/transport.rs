@@ -0,0 +1,143 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use jsonrpsee::core::{async_trait, Error, JsonRawValue};
+use jsonrpsee::core::client::{BatchResponse, ClientT};
+use jsonrpsee::core::params::BatchRequestBuilder;
+use jsonrpsee::core::traits::ToRpcParams;
+use jsonrpsee::types::{ErrorObject, Id, InvalidRequestId, NotificationSer, RequestSer, Response, ResponseSuccess};
+use serde::de::DeserializeOwned;
+use tokio::time::timeout;
+
+/// A way of moving raw JSON-RPC payloads to and from OpenSIPS, for MI transports that
+/// jsonrpsee doesn't provide.
+#[async_trait]
+pub trait MiTransport: Send + Sync {
+    /// Sends a request and returns the reply answering one of `ids`.
+    async fn round_trip(&self, request: String, ids: &[u64]) -> Result<Vec<u8>, Error>;
+    /// Sends a request that gets no reply.
+    async fn send(&self, notification: String) -> Result<(), Error>;
+}
+
+/// Whether a raw reply (single or batch) carries one of the given request ids.
+pub fn reply_matches(reply: &[u8], ids: &[u64]) -> bool {
+    let matches = |v: &serde_json::Value| v.get("id")
+        .and_then(|id| id.as_u64())
+        .is_some_and(|id| ids.contains(&id));
+    match serde_json::from_slice::<serde_json::Value>(reply) {
+        Ok(serde_json::Value::Array(replies)) => replies.iter().any(matches),
+        Ok(reply) => matches(&reply),
+        Err(_) => false,
+    }
+}
+
+/// JSON-RPC client over any [`MiTransport`], so the `OpenSIPSClient` methods work over it.
+pub struct MiClient<T> {
+    transport: T,
+    request_timeout: Duration,
+    next_id: AtomicU64,
+}
+
+impl<T> MiClient<T> {
+    pub fn new(transport: T) -> Self {
+        MiClient {
+            transport,
+            request_timeout: Duration::from_secs(60),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    fn next_ids(&self, count: u64) -> std::ops::Range<u64> {
+        let start = self.next_id.fetch_add(count, Ordering::Relaxed);
+        start..start + count
+    }
+
+    async fn with_timeout<R>(&self, fut: impl Future<Output = Result<R, Error>>) -> Result<R, Error> {
+        match timeout(self.request_timeout, fut).await {
+            Ok(result) => result,
+            Err(_) => Err(Error::RequestTimeout),
+        }
+    }
+}
+
+impl<T> fmt::Debug for MiClient<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MiClient")
+            .field("request_timeout", &self.request_timeout)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<T: MiTransport> ClientT for MiClient<T> {
+    async fn notification<Params>(&self, method: &str, params: Params) -> Result<(), Error>
+        where Params: ToRpcParams + Send
+    {
+        let params = params.to_rpc_params()?;
+        let raw = serde_json::to_string(&NotificationSer::borrowed(&method, params.as_deref()))?;
+        self.with_timeout(self.transport.send(raw)).await
+    }
+
+    async fn request<R, Params>(&self, method: &str, params: Params) -> Result<R, Error>
+        where R: DeserializeOwned,
+              Params: ToRpcParams + Send
+    {
+        let id = self.next_ids(1).start;
+        let params = params.to_rpc_params()?;
+        let raw = serde_json::to_string(&RequestSer::borrowed(&Id::Number(id), &method, params.as_deref()))?;
+        let body = self.with_timeout(self.transport.round_trip(raw, &[id])).await?;
+
+        let response = ResponseSuccess::try_from(serde_json::from_slice::<Response<&JsonRawValue>>(&body)?)?;
+        if response.id != Id::Number(id) {
+            return Err(InvalidRequestId::NotPendingRequest(response.id.to_string()).into());
+        }
+        Ok(serde_json::from_str(response.result.get())?)
+    }
+
+    async fn batch_request<'a, R>(&self, batch: BatchRequestBuilder<'a>) -> Result<BatchResponse<'a, R>, Error>
+        where R: DeserializeOwned + fmt::Debug + 'a
+    {
+        let batch = batch.build()?;
+        let ids = self.next_ids(batch.len() as u64);
+        let requests: Vec<_> = batch.into_iter()
+            .zip(ids.clone())
+            .map(|((method, params), id)| RequestSer::owned(Id::Number(id), method, params))
+            .collect();
+        let raw = serde_json::to_string(&requests)?;
+        let id_list: Vec<u64> = ids.clone().collect();
+        let body = self.with_timeout(self.transport.round_trip(raw, &id_list)).await?;
+
+        let replies: Vec<Response<&JsonRawValue>> = serde_json::from_slice(&body)?;
+        let mut responses: Vec<_> = ids.clone().map(|_| Err(ErrorObject::borrowed(0, "", None))).collect();
+        let mut successful_calls = 0;
+        let mut failed_calls = 0;
+        for reply in replies {
+            let id = reply.id.try_parse_inner_as_number()?;
+            let result = match ResponseSuccess::try_from(reply) {
+                Ok(r) => {
+                    successful_calls += 1;
+                    Ok(serde_json::from_str(r.result.get())?)
+                }
+                Err(e) => {
+                    failed_calls += 1;
+                    Err(e)
+                }
+            };
+            match id.checked_sub(ids.start).and_then(|i| responses.get_mut(i as usize)) {
+                Some(slot) => *slot = result,
+                None => return Err(InvalidRequestId::NotPendingRequest(id.to_string()).into()),
+            }
+        }
+        Ok(BatchResponse::new(successful_calls, responses, failed_calls))
+    }
+}
@@ -0,0 +1,612 @@
+//! B2B user agent sessions driven through the `ua_session_*` MI commands and `E_UA_SESSION`.
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use super::*;
+use super::b2b_entities::B2BState;
+use super::sdp::{is_sdp, Sdp, SdpError};
+use super::sip::{NameAddr, SipHeaders};
+
+// events for keys nobody has claimed yet, e.g. those racing ua_session_client_start's reply
+const MAX_ORPHANS: usize = 1024;
+
+#[derive(Debug)]
+pub enum UaSessionError {
+    Rpc(jsonrpsee::core::Error),
+    /// The action isn't valid in the session's current state.
+    InvalidState { state: UAEventType, action: &'static str },
+}
+
+impl fmt::Display for UaSessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UaSessionError::Rpc(e) => write!(f, "MI error: {}", e),
+            UaSessionError::InvalidState { state, action } => write!(f, "cannot {} a session in state {:?}", action, state),
+        }
+    }
+}
+
+impl std::error::Error for UaSessionError {}
+
+impl From<jsonrpsee::core::Error> for UaSessionError {
+    fn from(e: jsonrpsee::core::Error) -> Self {
+        UaSessionError::Rpc(e)
+    }
+}
+
+impl UAEventType {
+    pub fn is_final(&self) -> bool {
+        matches!(self, UAEventType::Rejected | UAEventType::Terminated)
+    }
+
+    /// Whether a session in this state may move to `next`.
+    pub fn can_become(&self, next: &UAEventType) -> bool {
+        use UAEventType::*;
+        match self {
+            New => !matches!(next, New),
+            Early => matches!(next, Early | Answered | Rejected | Terminated),
+            Answered | Updated => matches!(next, Updated | Terminated),
+            Rejected | Terminated => false,
+        }
+    }
+}
+
+/// What MI commands answer for a key they don't know.
+const MI_NOT_FOUND: i32 = 404;
+
+/// Where the `ua_session_list` state puts a session, if it says anything.
+fn state_from_list(state: B2BState) -> Option<UAEventType> {
+    match state {
+        B2BState::Undefined => None,
+        B2BState::New | B2BState::NewAuth => Some(UAEventType::New),
+        B2BState::Early => Some(UAEventType::Early),
+        B2BState::Confirmed | B2BState::Established => Some(UAEventType::Answered),
+        B2BState::Modified => Some(UAEventType::Updated),
+        B2BState::Terminated => Some(UAEventType::Terminated),
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    sessions: HashMap<String, mpsc::UnboundedSender<UASession>>,
+    orphans: HashMap<String, Vec<UASession>>,
+    orphan_order: VecDeque<String>,
+    incoming: Option<mpsc::UnboundedSender<UASession>>,
+}
+
+/// Correlates `E_UA_SESSION` events with the sessions they belong to. Feed it every event, e.g.
+/// from `EventRouter::on_ua_session`; clones share the same sessions.
+pub struct UaSessionManager<C> {
+    client: Arc<C>,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl<C> Clone for UaSessionManager<C> {
+    fn clone(&self) -> Self {
+        UaSessionManager { client: self.client.clone(), registry: self.registry.clone() }
+    }
+}
+
+/// Parameters for `ua_session_client_start`.
+#[derive(Clone, Debug, Default)]
+pub struct Outbound {
+    pub ruri: String,
+    pub to: String,
+    pub from: String,
+    pub proxy: String,
+    pub body: String,
+    pub extra_headers: Vec<String>,
+    pub content_type: String,
+    pub flags: String,
+}
+
+impl<C: OpenSIPSClient + Send + Sync + 'static> UaSessionManager<C> {
+    pub fn new(client: Arc<C>) -> Self {
+        UaSessionManager { client, registry: Default::default() }
+    }
+
+    pub fn client(&self) -> &Arc<C> {
+        &self.client
+    }
+
+    /// Routes one event to its session. Returns false when nobody claimed it.
+    pub fn handle_event(&self, event: UASession) -> bool {
+        let mut registry = self.registry.lock().unwrap();
+        let event = match registry.sessions.get(&event.key) {
+            Some(sender) => match sender.send(event) {
+                Ok(()) => return true,
+                // the handle is gone; treat the event as unknown
+                Err(e) => e.0,
+            },
+            None => event,
+        };
+        if event.event_type == UAEventType::New && event.entity_type == "UAS" {
+            if let Some(incoming) = &registry.incoming {
+                if incoming.send(event.clone()).is_ok() {
+                    return true;
+                }
+            }
+        }
+        debug!("no session for UA event {} ({:?})", event.key, event.event_type);
+        if !registry.orphans.contains_key(&event.key) {
+            registry.orphan_order.push_back(event.key.clone());
+            if registry.orphan_order.len() > MAX_ORPHANS {
+                let oldest = registry.orphan_order.pop_front().expect("queue is not empty");
+                registry.orphans.remove(&oldest);
+            }
+        }
+        registry.orphans.entry(event.key.clone()).or_default().push(event);
+        false
+    }
+
+    /// New sessions created by OpenSIPS (server mode) that no handle exists for yet.
+    pub fn incoming(&self) -> mpsc::UnboundedReceiver<UASession> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.registry.lock().unwrap().incoming = Some(tx);
+        rx
+    }
+
+    /// Places a call with `ua_session_client_start`.
+    pub async fn start(&self, call: Outbound) -> Result<UaSessionHandle<C>, UaSessionError> {
+        let key = self.client.ua_session_client_start(
+            call.ruri, call.to, call.from, call.proxy, call.body, call.extra_headers, call.content_type, call.flags,
+        ).await?;
+        Ok(self.attach(key, UAEventType::New))
+    }
+
+    /// Takes over an existing session, e.g. one announced on `incoming`.
+    pub fn attach(&self, key: String, state: UAEventType) -> UaSessionHandle<C> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut registry = self.registry.lock().unwrap();
+        for event in registry.orphans.remove(&key).unwrap_or_default() {
+            let _ = tx.send(event);
+        }
+        registry.orphan_order.retain(|k| *k != key);
+        registry.sessions.insert(key.clone(), tx);
+        UaSessionHandle { manager: self.clone(), key, state, events: rx }
+    }
+
+    fn detach(&self, key: &str) {
+        self.registry.lock().unwrap().sessions.remove(key);
+    }
+}
+
+/// One session. Dropping it stops routing events to it.
+pub struct UaSessionHandle<C: OpenSIPSClient + Send + Sync + 'static> {
+    manager: UaSessionManager<C>,
+    key: String,
+    state: UAEventType,
+    events: mpsc::UnboundedReceiver<UASession>,
+}
+
+impl<C: OpenSIPSClient + Send + Sync + 'static> UaSessionHandle<C> {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn state(&self) -> &UAEventType {
+        &self.state
+    }
+
+    /// The next event that moves the session, or `None` once it has ended. Events that would be
+    /// an invalid transition are logged and skipped.
+    pub async fn next(&mut self) -> Option<UASession> {
+        while !self.state.is_final() {
+            let event = self.events.recv().await?;
+            if !self.state.can_become(&event.event_type) {
+                warn!("ignoring {:?} for session {} in state {:?}", event.event_type, self.key, self.state);
+                continue;
+            }
+            self.set_state(event.event_type.clone());
+            return Some(event);
+        }
+        None
+    }
+
+    fn set_state(&mut self, state: UAEventType) {
+        self.state = state;
+        if self.state.is_final() {
+            self.manager.detach(&self.key);
+        }
+    }
+
+    fn check(&self, action: &'static str, allowed: impl Fn(&UAEventType) -> bool) -> Result<(), UaSessionError> {
+        if allowed(&self.state) {
+            Ok(())
+        } else {
+            Err(UaSessionError::InvalidState { state: self.state.clone(), action })
+        }
+    }
+
+    /// Sends a reply to the pending INVITE; the state follows from the code.
+    pub async fn reply(&mut self, code: usize, reason: &str, body: &str, content_type: &str, extra_headers: Vec<String>) -> Result<(), UaSessionError> {
+        self.reply_to("INVITE", code, reason, body, content_type, extra_headers).await
+    }
+
+    async fn reply_to(&mut self, method: &str, code: usize, reason: &str, body: &str, content_type: &str, extra_headers: Vec<String>) -> Result<(), UaSessionError> {
+        self.check("reply to", |s| matches!(s, UAEventType::New | UAEventType::Early))?;
+        self.manager.client.ua_session_reply(
+            self.key.clone(), method.to_string(), code, reason.to_string(), body.to_string(), extra_headers, content_type.to_string(),
+        ).await?;
+        match code {
+            100..=199 => self.set_state(UAEventType::Early),
+            200..=299 => self.set_state(UAEventType::Answered),
+            _ => self.set_state(UAEventType::Rejected),
+        }
+        Ok(())
+    }
+
+    pub async fn answer(&mut self, body: &str, content_type: &str) -> Result<(), UaSessionError> {
+        self.reply(200, "OK", body, content_type, Vec::new()).await
+    }
+
+    pub async fn reject(&mut self, code: usize, reason: &str) -> Result<(), UaSessionError> {
+        self.reply(code, reason, "", "", Vec::new()).await
+    }
+
+    /// Sends an in-dialog request such as a re-INVITE or INFO.
+    pub async fn update(&mut self, method: &str, body: &str, content_type: &str, extra_headers: Vec<String>) -> Result<(), UaSessionError> {
+        self.check("update", |s| matches!(s, UAEventType::Answered | UAEventType::Updated))?;
+        self.manager.client.ua_session_update(
+            self.key.clone(), method.to_string(), body.to_string(), extra_headers, content_type.to_string(),
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn hangup(&mut self) -> Result<(), UaSessionError> {
+        self.check("hang up", |s| !s.is_final())?;
+        self.manager.client.ua_session_terminate(self.key.clone(), Vec::new()).await?;
+        self.set_state(UAEventType::Terminated);
+        Ok(())
+    }
+
+    /// Asks OpenSIPS for the session's state, for when events may have been lost. A session
+    /// `ua_session_list` no longer knows about is considered terminated; a listed state the
+    /// session can't move to from its current one is ignored as stale.
+    pub async fn reconcile(&mut self) -> Result<&UAEventType, UaSessionError> {
+        let state = match self.manager.client.ua_session_list_with_key(self.key.clone()).await {
+            Ok(session) => state_from_list(session.state),
+            Err(jsonrpsee::core::Error::Call(e)) if e.code() == MI_NOT_FOUND => {
+                debug!("session {} not listed: {}", self.key, e);
+                Some(UAEventType::Terminated)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match state {
+            Some(state) if state != self.state && self.state.can_become(&state) => {
+                debug!("session {} reconciled from {:?} to {:?}", self.key, self.state, state);
+                self.set_state(state);
+            }
+            Some(state) if state != self.state => debug!("session {} in {:?}, ignoring listed {:?}", self.key, self.state, state),
+            _ => {}
+        }
+        Ok(&self.state)
+    }
+}
+
+impl<C: OpenSIPSClient + Send + Sync + 'static> Drop for UaSessionHandle<C> {
+    fn drop(&mut self) {
+        self.manager.detach(&self.key);
+    }
+}
+
+/// What an unanswered incoming call is failed with.
+#[derive(Clone, Debug)]
+pub struct AnswerTimeout {
+    pub after: Duration,
+    pub code: usize,
+    pub reason: String,
+}
+
+impl Default for AnswerTimeout {
+    fn default() -> Self {
+        AnswerTimeout { after: Duration::from_secs(30), code: 480, reason: "Temporarily Unavailable".to_string() }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Deadline {
+    Pending,
+    /// a final reply is on its way
+    Replying,
+    /// the timer fired while a final reply was on its way
+    Overdue,
+    /// replied to, hung up or handed over
+    Disarmed,
+    Expired,
+}
+
+/// An INVITE OpenSIPS handed to ua_session, announced by an `E_UA_SESSION` `New` event.
+pub struct IncomingCall<C: OpenSIPSClient + Send + Sync + 'static> {
+    session: UaSessionHandle<C>,
+    method: String,
+    pub from: Option<NameAddr>,
+    pub to: Option<NameAddr>,
+    /// from `ua_session_list`, as the event doesn't carry it
+    pub request_uri: Option<String>,
+    pub headers: SipHeaders,
+    pub content_type: Option<String>,
+    pub body: String,
+    timeout: Option<AnswerTimeout>,
+    deadline: Arc<Mutex<Deadline>>,
+}
+
+impl<C: OpenSIPSClient + Send + Sync + 'static> UaSessionManager<C> {
+    /// Takes charge of a new server session. Unless it gets a final reply within
+    /// `timeout`, it is failed with the configured code.
+    pub async fn incoming_call(&self, event: UASession, timeout: Option<AnswerTimeout>) -> IncomingCall<C> {
+        let request_uri = match self.client.ua_session_list_with_key(event.key.clone()).await {
+            Ok(session) => Some(session.ruri),
+            Err(e) => {
+                debug!("no ua_session_list entry for {}: {}", event.key, e);
+                None
+            }
+        };
+        let deadline = Arc::new(Mutex::new(Deadline::Pending));
+        if let Some(timeout) = timeout.clone() {
+            tokio::spawn(expire(self.client.clone(), event.key.clone(), event.method.clone(), timeout, deadline.clone()));
+        }
+        let headers = event.sip_headers();
+        IncomingCall {
+            from: headers.get("From").and_then(NameAddr::parse),
+            to: headers.get("To").and_then(NameAddr::parse),
+            content_type: headers.get("Content-Type").map(str::to_string),
+            session: self.attach(event.key, UAEventType::New),
+            method: event.method,
+            request_uri,
+            headers,
+            body: event.body,
+            timeout,
+            deadline,
+        }
+    }
+}
+
+async fn expire<C: OpenSIPSClient + Send + Sync>(client: Arc<C>, key: String, method: String, timeout: AnswerTimeout, deadline: Arc<Mutex<Deadline>>) {
+    tokio::time::sleep(timeout.after).await;
+    {
+        let mut deadline = deadline.lock().unwrap();
+        match *deadline {
+            Deadline::Pending => *deadline = Deadline::Expired,
+            // left to the reply, in case it fails
+            Deadline::Replying => {
+                *deadline = Deadline::Overdue;
+                return;
+            }
+            _ => return,
+        }
+    }
+    debug!("call {} not answered in {:?}, replying {}", key, timeout.after, timeout.code);
+    fail_unanswered(client.as_ref(), key, method, timeout).await;
+}
+
+async fn fail_unanswered<C: OpenSIPSClient + Send + Sync>(client: &C, key: String, method: String, timeout: AnswerTimeout) {
+    if let Err(e) = client.ua_session_reply(key.clone(), method, timeout.code, timeout.reason, String::new(), Vec::new(), String::new()).await {
+        warn!("failed to fail unanswered call {}: {}", key, e);
+    }
+}
+
+impl<C: OpenSIPSClient + Send + Sync + 'static> IncomingCall<C> {
+    pub fn key(&self) -> &str {
+        self.session.key()
+    }
+
+    pub fn state(&self) -> &UAEventType {
+        self.session.state()
+    }
+
+    /// The offer, when the INVITE carries SDP.
+    pub fn sdp(&self) -> Result<Option<Sdp>, SdpError> {
+        if !self.content_type.as_deref().is_some_and(is_sdp) || self.body.is_empty() {
+            return Ok(None);
+        }
+        self.body.parse().map(Some)
+    }
+
+    pub async fn reply(&mut self, code: usize, reason: &str, body: &str, content_type: &str, extra_headers: Vec<String>) -> Result<(), UaSessionError> {
+        let method = self.method.clone();
+        // provisional replies leave the timer armed, but not after it has failed the call
+        let armed = {
+            let mut deadline = self.deadline.lock().unwrap();
+            match *deadline {
+                Deadline::Expired => {
+                    drop(deadline);
+                    self.session.set_state(UAEventType::Rejected);
+                    return Err(UaSessionError::InvalidState { state: UAEventType::Rejected, action: "reply to" });
+                }
+                Deadline::Pending if code >= 200 => {
+                    *deadline = Deadline::Replying;
+                    true
+                }
+                _ => false,
+            }
+        };
+        let result = self.session.reply_to(&method, code, reason, body, content_type, extra_headers).await;
+        if !armed {
+            return result;
+        }
+        let overdue = {
+            let mut deadline = self.deadline.lock().unwrap();
+            let overdue = *deadline == Deadline::Overdue;
+            *deadline = match (&result, overdue) {
+                (Ok(_), _) => Deadline::Disarmed,
+                (Err(_), true) => Deadline::Expired,
+                (Err(_), false) => Deadline::Pending,
+            };
+            overdue
+        };
+        if result.is_err() && overdue {
+            if let Some(timeout) = self.timeout.clone() {
+                debug!("reply to call {} failed after its answer timeout, replying {}", self.key(), timeout.code);
+                fail_unanswered(self.session.manager.client.as_ref(), self.key().to_string(), method, timeout).await;
+            }
+        }
+        result
+    }
+
+    pub async fn ringing(&mut self) -> Result<(), UaSessionError> {
+        self.reply(180, "Ringing", "", "", Vec::new()).await
+    }
+
+    /// 183 with early media.
+    pub async fn session_progress(&mut self, sdp: &str) -> Result<(), UaSessionError> {
+        self.reply(183, "Session Progress", sdp, "application/sdp", Vec::new()).await
+    }
+
+    pub async fn answer(&mut self, sdp: &str) -> Result<(), UaSessionError> {
+        self.reply(200, "OK", sdp, "application/sdp", Vec::new()).await
+    }
+
+    pub async fn reject(&mut self, code: usize, reason: &str) -> Result<(), UaSessionError> {
+        self.reply(code, reason, "", "", Vec::new()).await
+    }
+
+    pub async fn hangup(&mut self) -> Result<(), UaSessionError> {
+        self.session.hangup().await?;
+        self.disarm();
+        Ok(())
+    }
+
+    /// The session, for following it after the call is answered. The answer timeout no longer
+    /// applies.
+    pub fn into_session(self) -> UaSessionHandle<C> {
+        self.disarm();
+        self.session
+    }
+
+    fn disarm(&self) {
+        let mut deadline = self.deadline.lock().unwrap();
+        if *deadline == Deadline::Pending {
+            *deadline = Deadline::Disarmed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::mock::{MockError, MockOpenSIPS};
+
+    fn event(key: &str, event_type: UAEventType) -> UASession {
+        UASession {
+            key: key.into(),
+            entity_type: "UAC".into(),
+            event_type,
+            status: 0,
+            reason: String::new(),
+            method: "INVITE".into(),
+            body: String::new(),
+            headers: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_lifecycle() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        mock.respond("ua_session_client_start", "key1");
+        mock.respond("ua_session_update", "OK");
+        mock.respond("ua_session_terminate", "OK");
+        let manager = UaSessionManager::new(Arc::new(mock.client()));
+
+        // arrives before client_start has answered
+        assert!(!manager.handle_event(event("key1", UAEventType::Early)));
+        let mut session = manager.start(Outbound { ruri: "sip:bob@example.com".into(), ..Default::default() }).await.unwrap();
+        assert_eq!(session.next().await.unwrap().event_type, UAEventType::Early);
+
+        assert!(matches!(session.update("INVITE", "", "", vec![]).await, Err(UaSessionError::InvalidState { action: "update", .. })));
+        assert!(manager.handle_event(event("key1", UAEventType::New)));
+        assert!(manager.handle_event(event("key1", UAEventType::Answered)));
+        assert_eq!(session.next().await.unwrap().event_type, UAEventType::Answered);
+        session.update("INVITE", "v=0", "application/sdp", vec![]).await.unwrap();
+
+        session.hangup().await.unwrap();
+        assert!(session.next().await.is_none());
+        assert!(session.reject(486, "Busy Here").await.is_err());
+        assert!(!manager.handle_event(event("key1", UAEventType::Terminated)));
+
+        mock.respond("ua_session_list", json!({
+            "dlg": 0, "logic_key": "", "mod_name": "ua_session", "state": 5, "last_invite_cseq": 1,
+            "last_method": 0, "last_reply_code": 200, "db_flag": 0, "ruri": "", "callid": "c", "from": "",
+            "from_uri": "", "from_tag": "", "to": "", "to_uri": "", "to_tag": "",
+            "cseq": {"caller": 1, "callee": 1}, "contact": {"caller": "", "callee": ""}, "send_sock": "", "tm_tran": "",
+        }));
+        let mut other = manager.attach("key2".into(), UAEventType::Early);
+        assert_eq!(*other.reconcile().await.unwrap(), UAEventType::Answered);
+        // a stale listing doesn't move the session back
+        mock.respond("ua_session_list", json!({
+            "dlg": 0, "logic_key": "", "mod_name": "ua_session", "state": 3, "last_invite_cseq": 1,
+            "last_method": 0, "last_reply_code": 180, "db_flag": 0, "ruri": "", "callid": "c", "from": "",
+            "from_uri": "", "from_tag": "", "to": "", "to_uri": "", "to_tag": "",
+            "cseq": {"caller": 1, "callee": 1}, "contact": {"caller": "", "callee": ""}, "send_sock": "", "tm_tran": "",
+        }));
+        assert_eq!(*other.reconcile().await.unwrap(), UAEventType::Answered);
+        // only not found means the session is gone
+        mock.fail("ua_session_list", MockError::new(-32602, "Invalid params"));
+        assert!(matches!(other.reconcile().await, Err(UaSessionError::Rpc(_))));
+        assert_eq!(*other.state(), UAEventType::Answered);
+        mock.fail("ua_session_list", MockError::new(404, "Entity not found"));
+        assert_eq!(*other.reconcile().await.unwrap(), UAEventType::Terminated);
+    }
+
+    #[tokio::test]
+    async fn test_incoming_call_timeout() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        mock.respond("ua_session_reply", "OK");
+        mock.fail("ua_session_list", MockError::new(404, "Entity not found"));
+        let manager = UaSessionManager::new(Arc::new(mock.client()));
+        let mut incoming = manager.incoming();
+
+        let mut new = event("in1", UAEventType::New);
+        new.entity_type = "UAS".into();
+        new.headers = "f: \"Alice\" <sip:alice@example.com>;tag=a1\r\nTo: <sip:ivr@example.com>\r\nContent-Type: application/sdp\r\n".into();
+        assert!(manager.handle_event(new));
+        let new = incoming.recv().await.unwrap();
+        let timeout = AnswerTimeout { after: Duration::from_millis(20), ..Default::default() };
+        let mut call = manager.incoming_call(new, Some(timeout)).await;
+        assert_eq!(call.from.as_ref().unwrap().display_name.as_deref(), Some("Alice"));
+        assert_eq!(call.from.as_ref().unwrap().tag(), Some("a1"));
+        assert_eq!(call.to.as_ref().unwrap().uri, "sip:ivr@example.com");
+        assert_eq!(call.content_type.as_deref(), Some("application/sdp"));
+        assert_eq!(call.request_uri, None);
+        assert!(call.sdp().unwrap().is_none());
+
+        call.ringing().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(matches!(call.ringing().await, Err(UaSessionError::InvalidState { state: UAEventType::Rejected, .. })));
+        assert!(call.answer("v=0").await.is_err());
+        assert_eq!(*call.state(), UAEventType::Rejected);
+        let replies = mock.calls("ua_session_reply");
+        assert_eq!(replies.len(), 2);
+        assert_eq!((&replies[0]["code"], &replies[1]["code"]), (&json!(180), &json!(480)));
+    }
+
+    #[tokio::test]
+    async fn test_incoming_call_failed_reply_and_handover() {
+        let mock = MockOpenSIPS::start().await.unwrap();
+        mock.fail("ua_session_reply", MockError::new(500, "Internal error"));
+        mock.fail("ua_session_list", MockError::new(404, "Entity not found"));
+        let manager = UaSessionManager::new(Arc::new(mock.client()));
+        let timeout = AnswerTimeout { after: Duration::from_millis(20), ..Default::default() };
+
+        // a failed answer leaves the timer armed
+        let mut call = manager.incoming_call(event("in2", UAEventType::New), Some(timeout.clone())).await;
+        assert!(call.answer("v=0").await.is_err());
+        mock.respond("ua_session_reply", "OK");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let replies = mock.calls("ua_session_reply");
+        assert_eq!(replies.len(), 2);
+        assert_eq!((&replies[0]["code"], &replies[1]["code"]), (&json!(200), &json!(480)));
+        drop(call);
+
+        // a session taken over is left alone
+        let call = manager.incoming_call(event("in3", UAEventType::New), Some(timeout)).await;
+        let session = call.into_session();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(mock.calls("ua_session_reply").len(), 2);
+        drop(session);
+    }
+}
@@ -0,0 +1,425 @@
+//! Relays notifications to HTTP endpoints, for consumers that would rather not run a UDP
+//! listener.
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tracing::{debug, warn};
+use super::*;
+use super::dispatch::DispatchQueue;
+
+pub const EVENT_HEADER: &str = "x-opensips-event";
+/// Seconds since the Unix epoch when the request was sent, covered by the signature.
+pub const TIMESTAMP_HEADER: &str = "x-opensips-timestamp";
+/// `sha256=<hex HMAC of "<timestamp>.<body>">`, present when the route has a secret.
+pub const SIGNATURE_HEADER: &str = "x-opensips-signature";
+
+#[derive(Debug)]
+pub enum WebhookError {
+    InvalidUrl(String),
+    Http(hyper::Error),
+    Status(StatusCode),
+    Timeout,
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebhookError::InvalidUrl(url) => write!(f, "invalid endpoint url {:?}", url),
+            WebhookError::Http(e) => write!(f, "request failed: {}", e),
+            WebhookError::Status(s) => write!(f, "endpoint answered {}", s),
+            WebhookError::Timeout => write!(f, "request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for WebhookError {}
+
+impl From<hyper::Error> for WebhookError {
+    fn from(e: hyper::Error) -> Self {
+        WebhookError::Http(e)
+    }
+}
+
+/// An endpoint and the notifications it wants.
+#[derive(Clone, Debug)]
+pub struct WebhookRoute {
+    pub name: String,
+    pub url: Uri,
+    /// Event names to forward; empty forwards everything.
+    pub events: Vec<String>,
+    /// JSON pointers into the event params and the values they must equal.
+    pub fields: Vec<(String, Value)>,
+    pub secret: Option<Vec<u8>>,
+}
+
+impl WebhookRoute {
+    /// `url` must be an absolute `http://` or `https://` URL.
+    pub fn new(name: &str, url: &str) -> Result<Self, WebhookError> {
+        let invalid = || WebhookError::InvalidUrl(url.to_string());
+        let url: Uri = url.parse().map_err(|_| invalid())?;
+        if !matches!(url.scheme_str(), Some("http" | "https")) || url.authority().is_none() {
+            return Err(invalid());
+        }
+        Ok(WebhookRoute { name: name.to_string(), url, events: Vec::new(), fields: Vec::new(), secret: None })
+    }
+
+    pub fn event(mut self, event: &str) -> Self {
+        self.events.push(event.to_string());
+        self
+    }
+
+    /// Only forward events whose params have `value` at `pointer`, e.g. `("/domain", json!("example.com"))`.
+    pub fn field(mut self, pointer: &str, value: impl Into<Value>) -> Self {
+        self.fields.push((pointer.to_string(), value.into()));
+        self
+    }
+
+    pub fn secret(mut self, secret: impl Into<Vec<u8>>) -> Self {
+        self.secret = Some(secret.into());
+        self
+    }
+
+    /// `body` is the notification as sent, with `method` and `params`.
+    pub fn matches(&self, event: &str, body: &Value) -> bool {
+        (self.events.is_empty() || self.events.iter().any(|e| e == event))
+            && self.fields.iter().all(|(pointer, value)| body["params"].pointer(pointer) == Some(value))
+    }
+}
+
+fn mac(secret: &[u8], timestamp: u64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    mac
+}
+
+/// Hex encoded HMAC-SHA256 of the timestamp, a dot and `body`, as sent in the signature header.
+pub fn sign(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    mac(secret, timestamp, body).finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// For endpoints: whether the timestamp and signature headers match the body, and the request
+/// was sent no more than `max_age` before `now`, so that a captured request can't be replayed
+/// later on.
+pub fn verify(secret: &[u8], timestamp: &str, signature: &str, body: &[u8], max_age: Duration, now: SystemTime) -> bool {
+    let Ok(timestamp) = timestamp.parse::<u64>() else {
+        return false;
+    };
+    let sent = UNIX_EPOCH + Duration::from_secs(timestamp);
+    if now.duration_since(sent).is_ok_and(|age| age > max_age) {
+        return false;
+    }
+    let hex = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let bytes: Option<Vec<u8>> = hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Some(((*hi as char).to_digit(16)? << 4 | (*lo as char).to_digit(16)?) as u8),
+            _ => None,
+        })
+        .collect();
+    bytes.is_some_and(|bytes| mac(secret, timestamp, body).verify_slice(&bytes).is_ok())
+}
+
+// the platform's CA certificates; https deliveries fail to verify without them
+fn native_roots() -> rustls::RootCertStore {
+    let mut roots = rustls::RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            for cert in certs {
+                if let Err(e) = roots.add(&rustls::Certificate(cert.0)) {
+                    debug!("skipping unusable CA certificate: {:?}", e);
+                }
+            }
+        }
+        Err(e) => warn!("failed to load the platform's CA certificates: {:?}", e),
+    }
+    roots
+}
+
+fn https_client(tls: rustls::ClientConfig) -> Client<HttpsConnector<HttpConnector>> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder().build(connector)
+}
+
+/// A delivery that failed every attempt.
+#[derive(Debug)]
+pub struct DeliveryError {
+    pub route: String,
+    pub attempts: u32,
+    pub error: WebhookError,
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "delivery to {} failed after {} attempts: {}", self.route, self.attempts, self.error)
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+pub struct WebhookForwarder {
+    routes: Vec<WebhookRoute>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    request_timeout: Duration,
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    dead_letter: Option<Arc<Mutex<File>>>,
+    queue: DispatchQueue,
+}
+
+impl WebhookForwarder {
+    pub fn new(routes: Vec<WebhookRoute>) -> Self {
+        WebhookForwarder {
+            routes,
+            client: https_client(rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(native_roots())
+                .with_no_client_auth()),
+            request_timeout: Duration::from_secs(10),
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            dead_letter: None,
+            queue: DispatchQueue::default(),
+        }
+    }
+
+    /// TLS settings for `https://` routes, e.g. to trust a private CA; by default the platform's
+    /// CA certificates are trusted.
+    pub fn tls_config(mut self, tls: rustls::ClientConfig) -> Self {
+        self.client = https_client(tls);
+        self
+    }
+
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Retries double the delay after each failure, starting at `initial` and capped at `max`.
+    pub fn retries(mut self, max_attempts: u32, initial: Duration, max: Duration) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Bounds the background dispatch queue of `run`.
+    pub fn queue(mut self, queue: DispatchQueue) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Appends undeliverable notifications to `path` as JSON lines.
+    pub fn dead_letter(mut self, path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path.into())?;
+        self.dead_letter = Some(Arc::new(Mutex::new(file)));
+        Ok(self)
+    }
+
+    /// Delivers to every matching route concurrently, returning those that gave up.
+    pub async fn forward(&self, notification: &Notification) -> Vec<DeliveryError> {
+        let event = notification.event_name();
+        let body = serde_json::to_value(notification).expect("notifications serialize");
+        let payload = body.to_string();
+        let deliveries = self.routes.iter()
+            .filter(|route| route.matches(event, &body))
+            .map(|route| self.deliver(route, event, &payload));
+
+        let mut errors = Vec::new();
+        for result in join_all(deliveries).await {
+            if let Err(error) = result {
+                warn!("{}", error);
+                self.write_dead_letter(&error, &body);
+                errors.push(error);
+            }
+        }
+        errors
+    }
+
+    async fn deliver(&self, route: &WebhookRoute, event: &str, payload: &str) -> Result<(), DeliveryError> {
+        let mut backoff = self.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let error = match self.post(route, event, payload).await {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            if attempts >= self.max_attempts {
+                return Err(DeliveryError { route: route.name.clone(), attempts, error });
+            }
+            debug!("{} delivery attempt {} failed: {}", route.name, attempts, error);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+
+    async fn post(&self, route: &WebhookRoute, event: &str, payload: &str) -> Result<(), WebhookError> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(route.url.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event);
+        if let Some(secret) = &route.secret {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            request = request
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, timestamp, payload.as_bytes())));
+        }
+        let request = request.body(Body::from(payload.to_string())).expect("headers are valid");
+        let response = tokio::time::timeout(self.request_timeout, self.client.request(request))
+            .await
+            .map_err(|_| WebhookError::Timeout)??;
+        match response.status() {
+            s if s.is_success() => Ok(()),
+            s => Err(WebhookError::Status(s)),
+        }
+    }
+
+    fn write_dead_letter(&self, error: &DeliveryError, notification: &Value) {
+        let Some(file) = &self.dead_letter else {
+            return;
+        };
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64);
+        let line = json!({
+            "time": time,
+            "route": error.route,
+            "attempts": error.attempts,
+            "error": error.error.to_string(),
+            "notification": notification,
+        });
+        if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+            warn!("failed to write dead letter: {:?}", e);
+        }
+    }
+
+    /// Forwards everything the receiver decodes until its socket fails. Deliveries run in the
+    /// background so that slow endpoints don't hold up the socket.
+    pub async fn run(self, receiver: UdpNotificationReceiver) -> tokio::io::Result<()> {
+        let queue = self.queue;
+        let forwarder = Arc::new(self);
+        receiver.run_queued(queue, move |notification| {
+            let forwarder = forwarder.clone();
+            async move {
+                forwarder.forward(&notification).await;
+            }
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+
+    type Seen = Arc<Mutex<Vec<(String, Option<(String, String)>, String)>>>;
+
+    // answers 500 to the first request of each path, 200 afterwards, and 503 on /down
+    async fn endpoint() -> (SocketAddr, Seen) {
+        let seen: Seen = Default::default();
+        let service_seen = seen.clone();
+        let make_service = make_service_fn(move |_| {
+            let seen = service_seen.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let seen = seen.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let header = |name| req.headers().get(name).map(|v: &hyper::header::HeaderValue| v.to_str().unwrap().to_string());
+                        let signature = header(TIMESTAMP_HEADER).zip(header(SIGNATURE_HEADER));
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let mut seen = seen.lock().unwrap();
+                        let first = !seen.iter().any(|(p, _, _)| *p == path);
+                        seen.push((path.clone(), signature, String::from_utf8(body.to_vec()).unwrap()));
+                        let status = match (path.as_str(), first) {
+                            ("/down", _) => 503,
+                            (_, true) => 500,
+                            _ => 200,
+                        };
+                        Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, seen)
+    }
+
+    #[tokio::test]
+    async fn test_forward_retry_and_dead_letter() {
+        let (addr, seen) = endpoint().await;
+        let dead_letter = std::env::temp_dir().join(format!("opensips-webhook-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&dead_letter);
+        let forwarder = WebhookForwarder::new(vec![
+            WebhookRoute::new("dispatcher", &format!("http://{}/ds", addr)).unwrap()
+                .event("E_DISPATCHER_STATUS")
+                .field("/group", "1")
+                .secret("s3cret"),
+            WebhookRoute::new("contacts", &format!("http://{}/ul", addr)).unwrap().event("E_UL_CONTACT_INSERT"),
+            WebhookRoute::new("broken", &format!("http://{}/down", addr)).unwrap(),
+        ])
+            .retries(3, Duration::from_millis(1), Duration::from_millis(5))
+            .dead_letter(&dead_letter)
+            .unwrap();
+
+        let status = |group: &str| Notification::EDispatcherStatus(DispatcherStatus {
+            partition: "default".into(),
+            group: group.into(),
+            address: "sip:10.0.0.1".into(),
+            status: DispatcherState::Inactive,
+        });
+        let errors = forwarder.forward(&status("1")).await;
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].route.as_str(), errors[0].attempts), ("broken", 3));
+        forwarder.forward(&status("2")).await;
+
+        let seen = seen.lock().unwrap();
+        let ds: Vec<_> = seen.iter().filter(|(p, _, _)| p == "/ds").collect();
+        assert_eq!(ds.len(), 2);
+        let (_, signature, body) = ds[1];
+        let (timestamp, signature) = signature.as_ref().unwrap();
+        let max_age = Duration::from_secs(300);
+        let now = SystemTime::now();
+        assert!(verify(b"s3cret", timestamp, signature, body.as_bytes(), max_age, now));
+        assert!(!verify(b"s3cret", timestamp, signature, b"{}", max_age, now));
+        assert!(!verify(b"other", timestamp, signature, body.as_bytes(), max_age, now));
+        // a replay after max_age is refused
+        assert!(!verify(b"s3cret", timestamp, signature, body.as_bytes(), max_age, now + Duration::from_secs(301)));
+        assert!(!seen.iter().any(|(p, _, _)| p == "/ul"));
+
+        let dead: Vec<Value> = std::fs::read_to_string(&dead_letter).unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(dead.len(), 2);
+        assert_eq!(dead[0]["route"], "broken");
+        assert_eq!(dead[1]["notification"]["params"]["group"], "2");
+        let _ = std::fs::remove_file(&dead_letter);
+
+        for url in ["not a url", "/relative", "ftp://example.com/hook"] {
+            assert!(matches!(WebhookRoute::new("bad", url), Err(WebhookError::InvalidUrl(_))));
+        }
+        assert!(WebhookRoute::new("tls", "https://example.com/hook").is_ok());
+    }
+}